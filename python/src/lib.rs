@@ -4,22 +4,39 @@ extern crate tmsn;
 use pyo3::prelude::*;
 use pyo3::create_exception;
 use pyo3::wrap_pyfunction;
-use pyo3::exceptions::Exception;
+use pyo3::exceptions::PyException;
 
+use std::sync::Mutex;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+use std::time::Duration;
 
-use tmsn::network;
+use tmsn::Network;
+use tmsn::builder::NetworkBuilder;
+use tmsn::builder::NetworkMode;
+use tmsn::codec::AnyCodec;
+use tmsn::codec::Codec;
+use tmsn::error::TmsnError;
 
 
-create_exception!(tmsn, AddrInUse, Exception);
+create_exception!(tmsn, AddrInUse, PyException);
+create_exception!(tmsn, UnknownCodec, PyException);
+create_exception!(tmsn, NetworkError, PyException);
+
+
+fn to_py_err(err: TmsnError) -> PyErr {
+    match err {
+        TmsnError::Bind(_, _) => AddrInUse::new_err(err.to_string()),
+        _ => NetworkError::new_err(err.to_string()),
+    }
+}
 
 
 #[pyclass]
 pub struct TmsnNetwork {
+    codec: AnyCodec,
+    network: Option<Network<AnyCodec>>,
     remote_recv:  Option<Receiver<Vec<u8>>>,
-    local_sender: Option<Sender<Vec<u8>>>,
 }
 
 
@@ -28,28 +45,59 @@ impl TmsnNetwork {
     /// send out a packet
     /// Example: network.send(packet)
     pub fn send(&mut self, packet: &[u8]) -> PyResult<()> {
-        let ret = self.local_sender.as_mut().unwrap().send(packet.to_vec());
-        Ok(ret.unwrap())
+        if let Some(network) = self.network.as_ref() {
+            // raw packets are handed to the network as is, without going through serde
+            let ret = match self.codec {
+                AnyCodec::Raw => network.send_bytes(None, packet.to_vec()),
+                codec => codec.encode(&packet)
+                    .and_then(|payload| network.send_bytes(None, payload)),
+            };
+            return ret.map_err(to_py_err);
+        }
+        Err(NetworkError::new_err("The network has been shut down"))
+    }
+
+    /// wait until at least `min_peers` remote machines are connected, or `timeout_secs` has passed
+    /// Example: connected, missing = network.wait_ready(60.0, 2)
+    /// Returns the connected machines and the initial remote machines that are still missing
+    pub fn wait_ready(
+        &self, timeout_secs: f64, min_peers: usize,
+    ) -> PyResult<(Vec<String>, Vec<String>)> {
+        match self.network.as_ref() {
+            Some(network) => {
+                let report = network.wait_ready(Duration::from_secs_f64(timeout_secs), min_peers);
+                Ok((report.connected, report.missing))
+            },
+            None => Ok((vec![], vec![])),
+        }
+    }
+
+    /// get the address this machine is listening on, as `ip:port`
+    /// Example: addr = network.local_addr()
+    /// Returns None if the network does not listen, e.g. started with start_network_only_recv
+    pub fn local_addr(&self) -> PyResult<Option<String>> {
+        let local_addr = self.network.as_ref().and_then(|network| network.local_addr());
+        Ok(local_addr.map(|addr| addr.to_string()))
     }
 
     /// receive a packet
     /// Example: packet = network.recv()
     /// If there is no new packet, the method returns an empty list (i.e., [])
     pub fn recv(&mut self) -> PyResult<Vec<u8>> {
-        let ret = self.remote_recv.as_mut().unwrap().try_recv();
+        // a send-only network never receives a packet
+        let ret = match self.remote_recv.as_mut() {
+            Some(remote_recv) => remote_recv.try_recv(),
+            None => return Ok(vec![]),
+        };
         // TODO: handle exception?
-        if ret.is_ok() {
-            Ok(ret.unwrap())
-        } else {
-            Ok(vec![])
-        }
+        Ok(ret.unwrap_or_default())
     }
 }
 
 
 /// Starts a broadcast network using a subscription list.
 ///
-/// Example: start_network("machine_name", ["remote_ip_1", "remote_ip_2"], 8080)
+/// Example: start_network("machine_name", ["remote_ip_1", "remote_ip_2"], 8080, codec="raw")
 ///
 /// The network recieves as input a sender and a receiver of two channels, respectively,
 /// one for incoming packets and the other for outgoing packets.
@@ -63,10 +111,13 @@ impl TmsnNetwork {
 /// explicitly setting the list of IPs to be subscribed from each machine.
 ///
 /// ## Parameters
-/// * `name` - the local computer name.
-/// * `init_remote_ips` - a list of IPs to which this computer makes a connection initially.
-/// * `port` - the port number that the machines in the network are listening to.
-/// `port` has to be the same value for all machines.
+/// * `name` - the ID of the local computer, by which the other computers identify it.
+/// * `init_remote_ips` - a list of addresses to which this computer makes a connection initially,
+/// as `host:port`, or as `host` for the computers listening on the same port as this computer.
+/// * `port` - the port number that this computer is listening to,
+/// or 0 for a port assigned by the OS (see `network.local_addr()`).
+/// * `codec` - the encoding of the packets, one of `raw` (default), `json` and `bincode`.
+/// `codec` has to be the same value for all machines.
 ///
 /// Design
 ///
@@ -79,19 +130,36 @@ impl TmsnNetwork {
 /// computers that connected to it.
 ///
 #[pyfunction]
+#[pyo3(signature = (name, init_remote_ips, port, codec = "raw"))]
 pub fn start_network(
-    name: String, init_remote_ips: Vec<String>, port: u16,
+    name: String, init_remote_ips: Vec<String>, port: u16, codec: &str,
 ) -> PyResult<TmsnNetwork> {
+    let codec = match AnyCodec::from_name(codec) {
+        Some(codec) => codec,
+        None => return Err(UnknownCodec::new_err(format!("Unknown codec `{}`.", codec))),
+    };
     let (remote_s, remote_r) = mpsc::channel();
-    let (local_s, local_r) = mpsc::channel();
-    let is_network_on = network::start_network(
-        name.as_str(), &init_remote_ips, port, false, remote_s, local_r);
-    if is_network_on.is_err() {
-        return Err(AddrInUse::py_err(is_network_on.err().unwrap()));
-    }
+    let remote_s = Mutex::new(remote_s);
+    let network = NetworkBuilder::new(port)
+        .node_id(&name)
+        .remote_ips(&init_remote_ips)
+        .codec(codec)
+        .build_bytes(Box::new(move |_sender: String, payload: &[u8]| {
+            let packet = match codec {
+                AnyCodec::Raw => payload.to_vec(),
+                codec => match codec.decode(payload) {
+                    Ok(packet) => packet,
+                    // drop the packets that cannot be decoded
+                    Err(_) => return,
+                },
+            };
+            let _ = remote_s.lock().unwrap().send(packet);
+        }))
+        .map_err(to_py_err)?;
     let tmsn = TmsnNetwork {
+        codec,
+        network: Some(network),
         remote_recv: Some(remote_r),
-        local_sender: Some(local_s),
     };
     Ok(tmsn)
 }
@@ -99,17 +167,19 @@ pub fn start_network(
 
 /// Start the network in the send-out-data only mode.
 ///
-/// Example: start_network("machine_name", 8080)
+/// Example: start_network_only_send("machine_name", 8080)
 #[pyfunction]
 pub fn start_network_only_send(name: String, port: u16) -> PyResult<TmsnNetwork> {
-    let (local_s, local_r) = mpsc::channel();
-    let is_network_on = network::start_network_only_send(name.as_str(), port, local_r);
-    if is_network_on.is_err() {
-        return Err(AddrInUse::py_err(is_network_on.err().unwrap()));
-    }
+    let network = NetworkBuilder::new(port)
+        .node_id(&name)
+        .mode(NetworkMode::SendOnly)
+        .codec(AnyCodec::Raw)
+        .build_bytes(Box::new(|_sender: String, _payload: &[u8]| {}))
+        .map_err(to_py_err)?;
     let tmsn = TmsnNetwork {
+        codec: AnyCodec::Raw,
+        network: Some(network),
         remote_recv: None,
-        local_sender: Some(local_s),
     };
     Ok(tmsn)
 }
@@ -119,18 +189,26 @@ pub fn start_network_only_send(name: String, port: u16) -> PyResult<TmsnNetwork>
 /// The addresses of the remote machines to be listened to needs to be provided in
 /// the list of parameters.
 ///
-/// Example: start_network("machine_name", ["remote_ip_1", "remote_ip_2"], 8080)
+/// Example: start_network_only_recv("machine_name", ["remote_ip_1", "remote_ip_2"], 8080)
 #[pyfunction]
 pub fn start_network_only_recv(
     name: String, remote_ips: Vec<String>, port: u16,
 ) -> PyResult<TmsnNetwork> {
     let (remote_s, remote_r) = mpsc::channel();
-    let is_network_on =
-        network::start_network_only_recv(name.as_str(), &remote_ips, port, remote_s);
-    is_network_on.unwrap();
+    let remote_s = Mutex::new(remote_s);
+    let network = NetworkBuilder::new(port)
+        .node_id(&name)
+        .mode(NetworkMode::ReceiveOnly)
+        .remote_ips(&remote_ips)
+        .codec(AnyCodec::Raw)
+        .build_bytes(Box::new(move |_sender: String, payload: &[u8]| {
+            let _ = remote_s.lock().unwrap().send(payload.to_vec());
+        }))
+        .map_err(to_py_err)?;
     let tmsn = TmsnNetwork {
+        codec: AnyCodec::Raw,
+        network: Some(network),
         remote_recv: Some(remote_r),
-        local_sender: None,
     };
     Ok(tmsn)
 }
@@ -144,11 +222,14 @@ pub fn start_network_only_recv(
 ///     - start_network_only_send
 ///     - start_network_only_recv
 #[pymodule]
-fn tmsn(py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_wrapped(wrap_pyfunction!(start_network))?;
-    m.add_wrapped(wrap_pyfunction!(start_network_only_recv))?;
-    m.add_wrapped(wrap_pyfunction!(start_network_only_send))?;
+#[pyo3(name = "tmsn")]
+fn tmsn_module(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(start_network, m)?)?;
+    m.add_function(wrap_pyfunction!(start_network_only_recv, m)?)?;
+    m.add_function(wrap_pyfunction!(start_network_only_send, m)?)?;
     m.add("AddrInUse", py.get_type::<AddrInUse>())?;
+    m.add("UnknownCodec", py.get_type::<UnknownCodec>())?;
+    m.add("NetworkError", py.get_type::<NetworkError>())?;
 
     Ok(())
 }
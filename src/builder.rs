@@ -0,0 +1,484 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+
+use codec::Codec;
+use codec::JsonCodec;
+use error::TmsnError;
+use mock_network::MockNetwork;
+use network;
+use packet::WireFormat;
+use queue::QueuePolicy;
+use queue::SlowPeerPolicy;
+use real_network::RealNetwork;
+use retry::RetryPolicy;
+use BytesCallback;
+use Network;
+
+
+/// The directions in which a network exchanges the packets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Subscribe to the initial remote machines, and also to every machine that
+    /// subscribes to this machine (default)
+    #[default]
+    TwoWay,
+    /// Subscribe only to the initial remote machines, while still sending out the packets
+    /// to every machine that subscribes to this machine
+    OneWay,
+    /// Only send out the packets to the machines that subscribe to this machine,
+    /// e.g. a head node streaming the configurations.
+    /// No remote machines can be given.
+    SendOnly,
+    /// Only receive the packets from the initial remote machines, e.g. a monitoring node.
+    /// The port is not listened on, no heartbeats or receipts are sent out,
+    /// and `Network::send` fails.
+    ReceiveOnly,
+}
+
+
+/// Options of a network, collected by `NetworkBuilder`
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkConfig {
+    /// the port number that this machine is listening to, 0 for a port assigned by the OS
+    /// (see `Network::local_addr`), also used for the remote machines whose addresses
+    /// have no port
+    pub port: u16,
+    /// the local address to listen on, `0.0.0.0` by default
+    pub bind_addr: IpAddr,
+    /// the ID by which the remote machines identify this machine,
+    /// `None` for being identified by its address
+    pub node_id: Option<String>,
+    /// the addresses to which this machine makes a connection initially,
+    /// as `host:port`, or as `host` for the machines listening on `port`.
+    /// `host` is an IPv4 address, an IPv6 address in brackets, or a host name that is
+    /// resolved every time it is connected to.
+    pub remote_ips: Vec<String>,
+    /// the directions in which the packets are exchanged
+    pub mode: NetworkMode,
+    /// the time interval between sending out the heartbeat signals (unit: seconds)
+    pub heartbeat_interval_secs: u64,
+    /// how the connections to the remote machines are retried
+    pub retry_policy: RetryPolicy,
+    /// the encoding of the outgoing packets
+    pub wire_format: WireFormat,
+    /// the maximum number of packets waiting to be sent out, `None` for no limit
+    pub queue_capacity: Option<usize>,
+    /// what `Network::send` does when the outbound queue is full
+    pub queue_policy: QueuePolicy,
+    /// the maximum number of packets waiting to be written to each subscriber
+    pub peer_queue_capacity: usize,
+    /// what happens when a subscriber falls `peer_queue_capacity` packets behind
+    pub slow_peer_policy: SlowPeerPolicy,
+    /// the maximum number of the latest packets kept for each subscriber, which are replayed
+    /// if it reconnects after missing them, 0 for no replays.
+    /// It cannot exceed `peer_queue_capacity`.
+    pub replay_capacity: usize,
+    /// the maximum number of bytes of the packets kept for each subscriber to be replayed
+    pub replay_max_bytes: usize,
+    /// the time the packets kept for a subscriber that has disconnected are kept,
+    /// after which they are dropped
+    pub replay_retention: Duration,
+    /// the time after which a connection that receives nothing is considered lost,
+    /// `None` to wait forever. It should be longer than the heartbeat interval of the
+    /// remote machines, which keeps the idle connections alive.
+    pub read_timeout: Option<Duration>,
+    /// the time after which a connection that accepts no data is considered lost,
+    /// `None` to wait forever
+    pub write_timeout: Option<Duration>,
+    /// the idle time before the TCP keepalive probes are sent, `None` to send none
+    pub keepalive: Option<Duration>,
+    /// send out the small packets without delay, i.e. set `TCP_NODELAY`
+    pub nodelay: bool,
+    /// the time to wait for the acknowledgement of a packet sent with `Network::send_reliable`
+    /// before it is retransmitted
+    pub ack_timeout: Duration,
+    /// the maximum number of retransmissions of a packet sent with `Network::send_reliable`
+    /// before it is considered failed
+    pub max_retransmits: u32,
+    /// the time after which a remote machine that sent nothing, not even a heartbeat,
+    /// is suspected to have failed. It should be longer than the heartbeat interval of the
+    /// remote machines.
+    pub suspect_timeout: Duration,
+    /// the time after which a remote machine that sent nothing is considered dead
+    pub dead_timeout: Duration,
+    /// if set, the packets from each remote machine are delivered in the order they were sent
+    /// out, holding at most this many packets while waiting for a missing packet, which is
+    /// considered lost after that. `None` to deliver the packets as they arrive.
+    pub reorder_window: Option<usize>,
+    /// the time a missing packet is waited for in the ordered mode, after which it is
+    /// considered lost and the packets held for it are delivered
+    pub reorder_timeout: Duration,
+    /// the node ID or the address of the machine that receives the packets sent to
+    /// `HEAD_NODE`, `None` for the first machine that subscribed to this machine
+    pub head_node: Option<String>,
+}
+
+
+impl NetworkConfig {
+    /// Create the default configuration for the network listening to `port`
+    pub fn new(port: u16) -> NetworkConfig {
+        NetworkConfig {
+            port,
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            node_id: None,
+            remote_ips: vec![],
+            mode: NetworkMode::default(),
+            heartbeat_interval_secs: 30,
+            retry_policy: RetryPolicy::default(),
+            wire_format: WireFormat::default(),
+            queue_capacity: None,
+            queue_policy: QueuePolicy::default(),
+            peer_queue_capacity: 1024,
+            replay_capacity: 0,
+            replay_max_bytes: 64 << 20,
+            replay_retention: Duration::from_secs(60),
+            slow_peer_policy: SlowPeerPolicy::default(),
+            read_timeout: None,
+            write_timeout: Some(Duration::from_secs(60)),
+            keepalive: Some(Duration::from_secs(60)),
+            nodelay: false,
+            ack_timeout: Duration::from_secs(5),
+            max_retransmits: 3,
+            suspect_timeout: Duration::from_secs(90),
+            dead_timeout: Duration::from_secs(180),
+            reorder_window: None,
+            reorder_timeout: Duration::from_secs(1),
+            head_node: None,
+        }
+    }
+
+    /// Check if the configuration is valid
+    pub fn validate(&self) -> Result<(), TmsnError> {
+        if self.heartbeat_interval_secs == 0 {
+            return Err(TmsnError::InvalidConfig(
+                "`heartbeat_interval_secs` must be positive".to_string()));
+        }
+        let policy = &self.retry_policy;
+        if policy.max_attempts == Some(0) {
+            return Err(TmsnError::InvalidConfig(
+                "`retry_policy.max_attempts` must be positive".to_string()));
+        }
+        if !(policy.backoff_factor >= 1.0 && policy.backoff_factor.is_finite()) {
+            return Err(TmsnError::InvalidConfig(
+                "`retry_policy.backoff_factor` must be at least 1".to_string()));
+        }
+        if !(policy.jitter >= 0.0 && policy.jitter <= 1.0) {
+            return Err(TmsnError::InvalidConfig(
+                "`retry_policy.jitter` must be between 0 and 1".to_string()));
+        }
+        if policy.max_delay < policy.initial_delay {
+            return Err(TmsnError::InvalidConfig(
+                "`retry_policy.max_delay` must not be less than `initial_delay`".to_string()));
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(TmsnError::InvalidConfig("`queue_capacity` must be positive".to_string()));
+        }
+        if self.peer_queue_capacity == 0 {
+            return Err(TmsnError::InvalidConfig(
+                "`peer_queue_capacity` must be positive".to_string()));
+        }
+        if self.replay_capacity > self.peer_queue_capacity {
+            return Err(TmsnError::InvalidConfig(
+                "`replay_capacity` must not exceed `peer_queue_capacity`".to_string()));
+        }
+        if self.reorder_window == Some(0) {
+            return Err(TmsnError::InvalidConfig("`reorder_window` must be positive".to_string()));
+        }
+        let timeouts = [
+            ("read_timeout", self.read_timeout),
+            ("write_timeout", self.write_timeout),
+            ("keepalive", self.keepalive),
+            ("ack_timeout", Some(self.ack_timeout)),
+            ("suspect_timeout", Some(self.suspect_timeout)),
+            ("reorder_timeout", Some(self.reorder_timeout)),
+            ("replay_retention", Some(self.replay_retention)),
+        ];
+        for (name, timeout) in timeouts.iter() {
+            if *timeout == Some(Duration::from_secs(0)) {
+                return Err(TmsnError::InvalidConfig(format!("`{}` must be positive", name)));
+            }
+        }
+        if self.dead_timeout <= self.suspect_timeout {
+            return Err(TmsnError::InvalidConfig(
+                "`dead_timeout` must be longer than `suspect_timeout`".to_string()));
+        }
+        if self.mode == NetworkMode::SendOnly && !self.remote_ips.is_empty() {
+            return Err(TmsnError::InvalidConfig(
+                "`remote_ips` must be empty in the send-only mode".to_string()));
+        }
+        if self.node_id.as_ref().map(|node_id| node_id.is_empty()).unwrap_or(false) {
+            return Err(TmsnError::InvalidConfig("`node_id` must not be empty".to_string()));
+        }
+        if self.head_node.as_ref().map(|head_node| head_node.is_empty()).unwrap_or(false) {
+            return Err(TmsnError::InvalidConfig("`head_node` must not be empty".to_string()));
+        }
+        for addr in self.remote_ips.iter() {
+            if network::parse_addr(addr, self.port)?.port() == 0 {
+                return Err(TmsnError::InvalidConfig(format!(
+                    "the port of the remote address `{}` must be given, unless `port` is set",
+                    addr)));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Builder for configuring and creating a `Network`
+///
+/// Example:
+/// ```no_run
+/// use tmsn::builder::NetworkBuilder;
+/// use tmsn::codec::BincodeCodec;
+///
+/// let network = NetworkBuilder::new(8090)
+///     .remote_ips(&[String::from("127.0.0.1")])
+///     .heartbeat_interval_secs(5)
+///     .codec(BincodeCodec)
+///     .build(Box::new(|sender: String, model: Vec<f32>| {
+///         println!("Received a model of size {} from {}", model.len(), sender);
+///     }))
+///     .unwrap();
+/// ```
+pub struct NetworkBuilder<C: Codec = JsonCodec> {
+    config: NetworkConfig,
+    codec: C,
+    debug: bool,
+}
+
+
+impl NetworkBuilder<JsonCodec> {
+    /// Create a builder for the network listening to `port` (0 for a port assigned by the OS),
+    /// which encodes the payloads as JSON unless another codec is set
+    pub fn new(port: u16) -> NetworkBuilder<JsonCodec> {
+        NetworkBuilder {
+            config: NetworkConfig::new(port),
+            codec: JsonCodec,
+            debug: false,
+        }
+    }
+}
+
+
+impl<C: Codec> NetworkBuilder<C> {
+    /// Set the addresses to which this machine makes a connection initially,
+    /// as `host:port`, or as `host` for the machines listening on the same port as this machine
+    pub fn remote_ips(mut self, remote_ips: &[String]) -> NetworkBuilder<C> {
+        self.config.remote_ips = remote_ips.to_vec();
+        self
+    }
+
+    /// Set the ID by which the remote machines identify this machine, e.g. in the callbacks
+    /// and in `Network::get_subscribers` (default the address of this machine)
+    pub fn node_id(mut self, node_id: &str) -> NetworkBuilder<C> {
+        self.config.node_id = Some(node_id.to_string());
+        self
+    }
+
+    /// Set the directions in which the packets are exchanged (default `NetworkMode::TwoWay`)
+    pub fn mode(mut self, mode: NetworkMode) -> NetworkBuilder<C> {
+        self.config.mode = mode;
+        self
+    }
+
+    /// Set the local address to listen on (default `0.0.0.0`)
+    pub fn bind_addr(mut self, bind_addr: IpAddr) -> NetworkBuilder<C> {
+        self.config.bind_addr = bind_addr;
+        self
+    }
+
+    /// Set the initial time interval between the heartbeat signals (default 30 seconds)
+    pub fn heartbeat_interval_secs(mut self, secs: u64) -> NetworkBuilder<C> {
+        self.config.heartbeat_interval_secs = secs;
+        self
+    }
+
+    /// Set how the connections to the remote machines are retried
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> NetworkBuilder<C> {
+        self.config.retry_policy = retry_policy;
+        self
+    }
+
+    /// Set the initial encoding of the outgoing packets (default `WireFormat::Binary`)
+    pub fn wire_format(mut self, wire_format: WireFormat) -> NetworkBuilder<C> {
+        self.config.wire_format = wire_format;
+        self
+    }
+
+    /// Set the maximum number of packets waiting to be sent out (default no limit).
+    /// The heartbeats, the receipts and the retransmissions are queued up beyond it.
+    pub fn queue_capacity(mut self, capacity: usize) -> NetworkBuilder<C> {
+        self.config.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Set what `Network::send` does when the outbound queue is full
+    /// (default `QueuePolicy::Block`)
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> NetworkBuilder<C> {
+        self.config.queue_policy = policy;
+        self
+    }
+
+    /// Set the maximum number of packets waiting to be written to each subscriber
+    /// (default 1024)
+    pub fn peer_queue_capacity(mut self, capacity: usize) -> NetworkBuilder<C> {
+        self.config.peer_queue_capacity = capacity;
+        self
+    }
+
+    /// Set the maximum number of the latest packets kept for each subscriber to be replayed
+    /// if it reconnects, 0 to disable the replays (default 0)
+    pub fn replay_capacity(mut self, capacity: usize) -> NetworkBuilder<C> {
+        self.config.replay_capacity = capacity;
+        self
+    }
+
+    /// Set the maximum number of bytes of the packets kept for each subscriber to be replayed
+    /// (default 64 MiB)
+    pub fn replay_max_bytes(mut self, max_bytes: usize) -> NetworkBuilder<C> {
+        self.config.replay_max_bytes = max_bytes;
+        self
+    }
+
+    /// Set the time the packets kept for a subscriber that has disconnected are kept
+    /// (default 60 seconds)
+    pub fn replay_retention(mut self, retention: Duration) -> NetworkBuilder<C> {
+        self.config.replay_retention = retention;
+        self
+    }
+
+    /// Set what happens when a subscriber falls too far behind
+    /// (default `SlowPeerPolicy::Disconnect`)
+    pub fn slow_peer_policy(mut self, policy: SlowPeerPolicy) -> NetworkBuilder<C> {
+        self.config.slow_peer_policy = policy;
+        self
+    }
+
+    /// Set the time after which a connection that receives nothing is considered lost,
+    /// `None` for no limit (default)
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> NetworkBuilder<C> {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// Set the time after which a connection that accepts no data is considered lost,
+    /// `None` for no limit (default 60 seconds)
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> NetworkBuilder<C> {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Set the idle time before the TCP keepalive probes are sent,
+    /// `None` for no probes (default 60 seconds)
+    pub fn keepalive(mut self, time: Option<Duration>) -> NetworkBuilder<C> {
+        self.config.keepalive = time;
+        self
+    }
+
+    /// Set whether the small packets are sent out without delay, i.e. `TCP_NODELAY`
+    /// (default `false`)
+    pub fn nodelay(mut self, nodelay: bool) -> NetworkBuilder<C> {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Set the time to wait for the acknowledgement of a packet sent with
+    /// `Network::send_reliable` before it is retransmitted (default 5 seconds)
+    pub fn ack_timeout(mut self, timeout: Duration) -> NetworkBuilder<C> {
+        self.config.ack_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of retransmissions of a packet sent with
+    /// `Network::send_reliable` before it is considered failed (default 3)
+    pub fn max_retransmits(mut self, max_retransmits: u32) -> NetworkBuilder<C> {
+        self.config.max_retransmits = max_retransmits;
+        self
+    }
+
+    /// Set the time after which a remote machine that sent nothing is suspected to have failed
+    /// (default 90 seconds)
+    pub fn suspect_timeout(mut self, timeout: Duration) -> NetworkBuilder<C> {
+        self.config.suspect_timeout = timeout;
+        self
+    }
+
+    /// Set the time after which a remote machine that sent nothing is considered dead
+    /// (default 180 seconds)
+    pub fn dead_timeout(mut self, timeout: Duration) -> NetworkBuilder<C> {
+        self.config.dead_timeout = timeout;
+        self
+    }
+
+    /// Deliver the packets from each remote machine in the order they were sent out,
+    /// holding at most `window` packets while waiting for a missing packet
+    /// (default the packets are delivered as they arrive)
+    pub fn reorder_window(mut self, window: usize) -> NetworkBuilder<C> {
+        self.config.reorder_window = Some(window);
+        self
+    }
+
+    /// Set the time a missing packet is waited for when the packets are delivered in order,
+    /// after which it is considered lost (default 1 second)
+    pub fn reorder_timeout(mut self, timeout: Duration) -> NetworkBuilder<C> {
+        self.config.reorder_timeout = timeout;
+        self
+    }
+
+    /// Set the node ID or the address of the machine that receives the packets sent to
+    /// `HEAD_NODE` (default the first machine that subscribed to this machine)
+    pub fn head_node(mut self, head_node: &str) -> NetworkBuilder<C> {
+        self.config.head_node = Some(head_node.to_string());
+        self
+    }
+
+    /// Set to true to build a `MockNetwork` for the debugging purpose
+    pub fn debug(mut self, debug: bool) -> NetworkBuilder<C> {
+        self.debug = debug;
+        self
+    }
+
+    /// Set the encoding of the payloads, which has to be the same for all machines
+    pub fn codec<D: Codec>(self, codec: D) -> NetworkBuilder<D> {
+        NetworkBuilder {
+            config: self.config,
+            codec,
+            debug: self.debug,
+        }
+    }
+
+    /// Get the options collected so far
+    pub fn config(&self) -> &NetworkConfig {
+        &self.config
+    }
+
+    /// Validate the configuration, and create the network
+    ///
+    /// `callback` is called with the decoded payload when a new packet is received.
+    pub fn build<T: 'static + DeserializeOwned>(
+        self, callback: Box<dyn FnMut(String, T) + Sync + Send>,
+    ) -> Result<Network<C>, TmsnError> {
+        self.config.validate()?;
+        if self.debug {
+            Ok(Network::Mocked(MockNetwork::new(
+                self.config.port, &self.config.remote_ips, callback, self.codec)))
+        } else {
+            Ok(Network::Real(RealNetwork::with_config(self.config, callback, self.codec)?))
+        }
+    }
+
+    /// Validate the configuration, and create the network that passes the raw payload bytes
+    /// to `callback`
+    pub fn build_bytes(self, callback: BytesCallback) -> Result<Network<C>, TmsnError> {
+        self.config.validate()?;
+        if self.debug {
+            Ok(Network::Mocked(MockNetwork::new_bytes(
+                self.config.port, &self.config.remote_ips, callback, self.codec)))
+        } else {
+            Ok(Network::Real(RealNetwork::with_config_bytes(self.config, callback, self.codec)?))
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::fmt;
+
+use serde::de;
+use serde::de::DeserializeOwned;
+use serde::de::Visitor;
+use serde::de::value::SeqDeserializer;
+use serde::ser;
+use serde::ser::Impossible;
+use serde::ser::Serialize;
+
+use error::TmsnError;
+
+
+/// Encoding of the payloads passed to `Network::send` and to the receive callback
+///
+/// The codec is chosen when the `Network` is built, and has to be the same on all machines
+/// in the cluster.
+pub trait Codec: Send + Sync + 'static {
+    /// Encode a payload into the bytes to be sent out
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TmsnError>;
+    /// Decode the bytes received from the network into a payload
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TmsnError>;
+}
+
+
+/// Encode the payloads as JSON (default)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TmsnError> {
+        serde_json::to_vec(value).map_err(|err| TmsnError::Serialize(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TmsnError> {
+        serde_json::from_slice(bytes).map_err(|err| TmsnError::Deserialize(err.to_string()))
+    }
+}
+
+
+/// Encode the payloads in the compact binary format of `bincode`,
+/// suitable for large numeric vectors
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TmsnError> {
+        bincode::serialize(value).map_err(|err| TmsnError::Serialize(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TmsnError> {
+        bincode::deserialize(bytes).map_err(|err| TmsnError::Deserialize(err.to_string()))
+    }
+}
+
+
+/// Pass the payload bytes through unchanged
+///
+/// The payload has to be a byte sequence (e.g. `Vec<u8>`, `&[u8]`) or a string.
+/// Other types are rejected with an error.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawCodec;
+
+impl Codec for RawCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TmsnError> {
+        let mut serializer = RawSerializer { output: vec![] };
+        value.serialize(&mut serializer).map_err(|err| TmsnError::Serialize(err.0))?;
+        Ok(serializer.output)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TmsnError> {
+        T::deserialize(RawDeserializer { bytes }).map_err(|err| TmsnError::Deserialize(err.0))
+    }
+}
+
+
+/// A codec selected at runtime, e.g. from a configuration file or the Python bindings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnyCodec {
+    Json,
+    Bincode,
+    Raw,
+}
+
+impl AnyCodec {
+    /// Get the codec by its name, one of `json`, `bincode` and `raw`
+    pub fn from_name(name: &str) -> Option<AnyCodec> {
+        match name {
+            "json" => Some(AnyCodec::Json),
+            "bincode" => Some(AnyCodec::Bincode),
+            "raw" => Some(AnyCodec::Raw),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for AnyCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, TmsnError> {
+        match self {
+            AnyCodec::Json => JsonCodec.encode(value),
+            AnyCodec::Bincode => BincodeCodec.encode(value),
+            AnyCodec::Raw => RawCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, TmsnError> {
+        match self {
+            AnyCodec::Json => JsonCodec.decode(bytes),
+            AnyCodec::Bincode => BincodeCodec.decode(bytes),
+            AnyCodec::Raw => RawCodec.decode(bytes),
+        }
+    }
+}
+
+
+// Error reported by the serializer and the deserializer of `RawCodec`
+#[derive(Debug)]
+struct RawError(String);
+
+impl fmt::Display for RawError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RawError {}
+
+impl ser::Error for RawError {
+    fn custom<T: fmt::Display>(msg: T) -> RawError {
+        RawError(msg.to_string())
+    }
+}
+
+impl de::Error for RawError {
+    fn custom<T: fmt::Display>(msg: T) -> RawError {
+        RawError(msg.to_string())
+    }
+}
+
+fn unsupported<T>(kind: &str) -> Result<T, RawError> {
+    Err(RawError(format!("RawCodec cannot encode a {}, only bytes and strings.", kind)))
+}
+
+
+// Collects the bytes of a byte sequence or a string
+struct RawSerializer {
+    output: Vec<u8>,
+}
+
+macro_rules! reject_primitive {
+    ($($method:ident: $ty:ty),*) => {
+        $(
+            fn $method(self, _v: $ty) -> Result<(), RawError> {
+                unsupported(stringify!($ty))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Impossible<(), RawError>;
+    type SerializeTupleVariant = Impossible<(), RawError>;
+    type SerializeMap = Impossible<(), RawError>;
+    type SerializeStruct = Impossible<(), RawError>;
+    type SerializeStructVariant = Impossible<(), RawError>;
+
+    reject_primitive!(serialize_bool: bool, serialize_i8: i8, serialize_i16: i16,
+                      serialize_i32: i32, serialize_i64: i64, serialize_u16: u16,
+                      serialize_u32: u32, serialize_u64: u64, serialize_f32: f32,
+                      serialize_f64: f64, serialize_char: char);
+
+    fn serialize_u8(self, v: u8) -> Result<(), RawError> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), RawError> {
+        self.output.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), RawError> {
+        self.output.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), RawError> {
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), RawError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), RawError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), RawError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str,
+    ) -> Result<(), RawError> {
+        unsupported("enum")
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self, _name: &'static str, value: &T,
+    ) -> Result<(), RawError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T,
+    ) -> Result<(), RawError> {
+        unsupported("enum")
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, RawError> {
+        self.output.reserve(len.unwrap_or(0));
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, RawError> {
+        self.output.reserve(len);
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self, _name: &'static str, _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, RawError> {
+        unsupported("tuple struct")
+    }
+
+    fn serialize_tuple_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, RawError> {
+        unsupported("enum")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, RawError> {
+        unsupported("map")
+    }
+
+    fn serialize_struct(
+        self, _name: &'static str, _len: usize,
+    ) -> Result<Self::SerializeStruct, RawError> {
+        unsupported("struct")
+    }
+
+    fn serialize_struct_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeStructVariant, RawError> {
+        unsupported("enum")
+    }
+}
+
+impl ser::SerializeSeq for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), RawError> {
+        value.serialize(ByteSerializer { output: &mut self.output })
+    }
+
+    fn end(self) -> Result<(), RawError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut RawSerializer {
+    type Ok = ();
+    type Error = RawError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), RawError> {
+        value.serialize(ByteSerializer { output: &mut self.output })
+    }
+
+    fn end(self) -> Result<(), RawError> {
+        Ok(())
+    }
+}
+
+
+// Accepts a single `u8`, i.e. an element of a byte sequence
+struct ByteSerializer<'a> {
+    output: &'a mut Vec<u8>,
+}
+
+macro_rules! reject_element {
+    ($($method:ident: $ty:ty),*) => {
+        $(
+            fn $method(self, _v: $ty) -> Result<(), RawError> {
+                unsupported(concat!("sequence of ", stringify!($ty)))
+            }
+        )*
+    };
+}
+
+impl<'a> ser::Serializer for ByteSerializer<'a> {
+    type Ok = ();
+    type Error = RawError;
+    type SerializeSeq = Impossible<(), RawError>;
+    type SerializeTuple = Impossible<(), RawError>;
+    type SerializeTupleStruct = Impossible<(), RawError>;
+    type SerializeTupleVariant = Impossible<(), RawError>;
+    type SerializeMap = Impossible<(), RawError>;
+    type SerializeStruct = Impossible<(), RawError>;
+    type SerializeStructVariant = Impossible<(), RawError>;
+
+    reject_element!(serialize_bool: bool, serialize_i8: i8, serialize_i16: i16,
+                    serialize_i32: i32, serialize_i64: i64, serialize_u16: u16,
+                    serialize_u32: u32, serialize_u64: u64, serialize_f32: f32,
+                    serialize_f64: f64, serialize_char: char, serialize_str: &str,
+                    serialize_bytes: &[u8]);
+
+    fn serialize_u8(self, v: u8) -> Result<(), RawError> {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), RawError> {
+        unsupported("sequence of options")
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _value: &T) -> Result<(), RawError> {
+        unsupported("sequence of options")
+    }
+
+    fn serialize_unit(self) -> Result<(), RawError> {
+        unsupported("sequence of units")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), RawError> {
+        unsupported("sequence of units")
+    }
+
+    fn serialize_unit_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str,
+    ) -> Result<(), RawError> {
+        unsupported("sequence of enums")
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self, _name: &'static str, value: &T,
+    ) -> Result<(), RawError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _value: &T,
+    ) -> Result<(), RawError> {
+        unsupported("sequence of enums")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, RawError> {
+        unsupported("nested sequence")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, RawError> {
+        unsupported("nested sequence")
+    }
+
+    fn serialize_tuple_struct(
+        self, _name: &'static str, _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, RawError> {
+        unsupported("sequence of tuple structs")
+    }
+
+    fn serialize_tuple_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, RawError> {
+        unsupported("sequence of enums")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, RawError> {
+        unsupported("sequence of maps")
+    }
+
+    fn serialize_struct(
+        self, _name: &'static str, _len: usize,
+    ) -> Result<Self::SerializeStruct, RawError> {
+        unsupported("sequence of structs")
+    }
+
+    fn serialize_struct_variant(
+        self, _name: &'static str, _index: u32, _variant: &'static str, _len: usize,
+    ) -> Result<Self::SerializeStructVariant, RawError> {
+        unsupported("sequence of enums")
+    }
+}
+
+
+// Hands the received bytes to the payload type as a byte sequence or a string
+struct RawDeserializer<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'de, 'a> de::Deserializer<'de> for RawDeserializer<'a> {
+    type Error = RawError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        visitor.visit_bytes(self.bytes)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        if self.bytes.len() != 1 {
+            return Err(RawError(format!("Expect 1 byte, received {}.", self.bytes.len())));
+        }
+        visitor.visit_u8(self.bytes[0])
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        let s = std::str::from_utf8(self.bytes).map_err(|err| RawError(err.to_string()))?;
+        visitor.visit_str(s)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        if self.bytes.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self, _name: &'static str, visitor: V,
+    ) -> Result<V::Value, RawError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, RawError> {
+        let mut seq = SeqDeserializer::new(self.bytes.iter().cloned());
+        let value = visitor.visit_seq(&mut seq)?;
+        seq.end()?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self, _len: usize, visitor: V,
+    ) -> Result<V::Value, RawError> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit
+        unit_struct tuple_struct map struct enum identifier ignored_any
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use packet::Packet;
+use Outgoing;
+
+
+/// Delivery state of a packet sent out with `Network::send_reliable`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// waiting for the destination to acknowledge the packet
+    Pending,
+    /// the destination acknowledged the packet
+    Delivered,
+    /// the destination did not acknowledge the packet after all retransmissions,
+    /// or the network was shut down
+    Failed,
+}
+
+
+/// Handle to a packet sent out with `Network::send_reliable`
+#[derive(Clone)]
+pub struct DeliveryHandle {
+    state: Arc<(Mutex<DeliveryStatus>, Condvar)>,
+}
+
+
+impl DeliveryHandle {
+    pub(crate) fn new(status: DeliveryStatus) -> DeliveryHandle {
+        DeliveryHandle { state: Arc::new((Mutex::new(status), Condvar::new())) }
+    }
+
+    /// Get the current delivery state of the packet
+    pub fn status(&self) -> DeliveryStatus {
+        *self.lock()
+    }
+
+    /// Wait until the packet is delivered or has failed, for at most `timeout`.
+    /// Returns `DeliveryStatus::Pending` if it is still pending by then.
+    pub fn wait(&self, timeout: Duration) -> DeliveryStatus {
+        let status = self.lock();
+        let (status, _) = self.state.1
+            .wait_timeout_while(status, timeout, |status| *status == DeliveryStatus::Pending)
+            .unwrap_or_else(|err| err.into_inner());
+        *status
+    }
+
+    fn complete(&self, status: DeliveryStatus) {
+        *self.lock() = status;
+        self.state.1.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DeliveryStatus> {
+        self.state.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+
+// A packet waiting for the acknowledgement of its destination
+struct PendingPacket {
+    dest: Option<String>,
+    packet: Packet,
+    num_retransmits: u32,
+    deadline: Instant,
+    handle: DeliveryHandle,
+}
+
+
+/// Packets sent out with `Network::send_reliable` that are waiting for their
+/// acknowledgements, by their IDs
+#[derive(Clone)]
+pub struct Deliveries {
+    pending: Arc<Mutex<HashMap<u32, PendingPacket>>>,
+    ack_timeout: Duration,
+    max_retransmits: u32,
+}
+
+
+impl Deliveries {
+    /// Create the tracker of the packets that are retransmitted if not acknowledged within
+    /// `ack_timeout`, at most `max_retransmits` times
+    pub fn new(ack_timeout: Duration, max_retransmits: u32) -> Deliveries {
+        Deliveries {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            ack_timeout,
+            max_retransmits,
+        }
+    }
+
+    /// Start waiting for the acknowledgement of a packet, before it is sent out
+    pub fn track(&self, dest: Option<String>, packet: Packet) -> DeliveryHandle {
+        let handle = DeliveryHandle::new(DeliveryStatus::Pending);
+        self.lock().insert(packet.id, PendingPacket {
+            dest,
+            packet,
+            num_retransmits: 0,
+            deadline: Instant::now() + self.ack_timeout,
+            handle: handle.clone(),
+        });
+        handle
+    }
+
+    /// Stop waiting for the acknowledgement of a packet that could not be sent out
+    pub fn forget(&self, id: u32) {
+        self.lock().remove(&id);
+    }
+
+    /// Mark the packet `id` as delivered, as acknowledged by the remote machine `sender`.
+    /// The acknowledgements of the packets that are not pending, e.g. acknowledged already,
+    /// or that were sent to another machine, are ignored.
+    pub fn ack(&self, sender: &str, id: u32) {
+        let mut pending = self.lock();
+        if pending.get(&id).map(|p| p.dest.as_deref() == Some(sender)).unwrap_or(false) {
+            pending.remove(&id).unwrap().handle.complete(DeliveryStatus::Delivered);
+        }
+    }
+
+    /// Get the packets to be retransmitted because their acknowledgements did not arrive
+    /// in time, and mark the packets retransmitted too many times as failed
+    pub fn get_expired(&self) -> Vec<Outgoing> {
+        let now = Instant::now();
+        let mut pending = self.lock();
+        let expired: Vec<u32> = pending.iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut retransmits = vec![];
+        for id in expired {
+            let p = pending.get_mut(&id).unwrap();
+            if p.num_retransmits >= self.max_retransmits {
+                info!("Packet {} to {:?} was not acknowledged, giving up.", id, p.dest);
+                p.handle.complete(DeliveryStatus::Failed);
+                pending.remove(&id);
+                continue;
+            }
+            p.num_retransmits += 1;
+            p.deadline = now + self.ack_timeout;
+            trace!("Retransmitting packet {} to {:?}, attempt {}.", id, p.dest, p.num_retransmits);
+            retransmits.push((p.dest.clone(), p.packet.clone()));
+        }
+        retransmits
+    }
+
+    /// Mark all pending packets as failed
+    pub fn fail_all(&self) {
+        self.lock().drain().for_each(|(_, p)| p.handle.complete(DeliveryStatus::Failed));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, PendingPacket>> {
+        self.pending.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use packet::Packet;
+    use super::Deliveries;
+    use super::DeliveryStatus;
+
+    #[test]
+    fn test_acknowledgements() {
+        // the acknowledgements only count from the destination of the packet
+        let deliveries = Deliveries::new(Duration::from_secs(5), 0);
+        let packet = Packet::new(b"message".to_vec());
+        let handle = deliveries.track(Some(String::from("beta")), packet.clone());
+        deliveries.ack("gamma", packet.id);
+        assert_eq!(handle.status(), DeliveryStatus::Pending);
+        deliveries.ack("beta", packet.id);
+        assert_eq!(handle.status(), DeliveryStatus::Delivered);
+    }
+}
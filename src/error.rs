@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+
+/// Errors returned by the network
+#[derive(Debug)]
+pub enum TmsnError {
+    /// failed to bind the listening address
+    Bind(String, io::Error),
+    /// failed to connect to a remote address
+    Connect(String, io::Error),
+    /// the address of a remote machine cannot be parsed
+    InvalidAddress(String),
+    /// the network options are not valid
+    InvalidConfig(String),
+    /// failed to encode a payload
+    Serialize(String),
+    /// failed to decode a payload
+    Deserialize(String),
+    /// the destination is not a subscriber of this machine
+    UnknownPeer(String),
+    /// the remote machine speaks no protocol version that this machine speaks,
+    /// with the address of the remote machine and the reason
+    IncompatiblePeer(String, String),
+    /// the operation is not supported in the mode of the network
+    Unsupported(String),
+    /// the outbound queue is full
+    WouldBlock,
+    /// the network has been shut down
+    Closed,
+    /// the operation did not complete in time
+    Timeout,
+}
+
+
+impl fmt::Display for TmsnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TmsnError::Bind(addr, err) => write!(f, "Failed to bind `{}`: {}", addr, err),
+            TmsnError::Connect(addr, err) => write!(f, "Failed to connect to `{}`: {}", addr, err),
+            TmsnError::InvalidAddress(addr) => write!(f, "Invalid address `{}`", addr),
+            TmsnError::InvalidConfig(err) => write!(f, "Invalid configuration: {}", err),
+            TmsnError::Serialize(err) => write!(f, "Failed to encode the payload: {}", err),
+            TmsnError::Deserialize(err) => write!(f, "Failed to decode the payload: {}", err),
+            TmsnError::UnknownPeer(peer) => write!(f, "`{}` is not a subscriber", peer),
+            TmsnError::IncompatiblePeer(peer, reason) =>
+                write!(f, "Refused the incompatible peer `{}`: {}", peer, reason),
+            TmsnError::Unsupported(op) => write!(f, "{} is not supported in this mode", op),
+            TmsnError::WouldBlock => write!(f, "The outbound queue is full"),
+            TmsnError::Closed => write!(f, "The network has been shut down"),
+            TmsnError::Timeout => write!(f, "The operation timed out"),
+        }
+    }
+}
+
+
+impl Error for TmsnError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TmsnError::Bind(_, err) | TmsnError::Connect(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -15,38 +15,108 @@ tmsn = { path = "../tmsn" }
 */
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde;
+extern crate bincode;
 extern crate bufstream;
-extern crate serde;
+extern crate rand;
 extern crate serde_json;
+extern crate socket2;
 
 /// Struct for reporting the health of the network
 pub mod perfstats;
+/// Encoding of the payloads
+pub mod codec;
 /// The packet sent out via network
 pub mod packet;
 /// Network module
 pub mod real_network;
 /// Mock network module for the debugging purpose
 pub mod mock_network;
+/// Retry policy for the connections to the remote machines
+pub mod retry;
+/// Queue of the packets waiting to be sent out
+pub mod queue;
+/// Acknowledged delivery of the packets
+pub mod delivery;
+/// Failure detection and membership events of the remote machines
+pub mod liveness;
+/// Errors returned by the network
+pub mod error;
+/// Builder for configuring the network
+pub mod builder;
 /// Establish network connections between the workers in the cluster
 mod network;
 
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::mpsc::Receiver;
 use std::sync::mpsc::TryRecvError;
+use std::time::Duration;
 
-use bufstream::BufStream;
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
+use builder::NetworkBuilder;
+use codec::Codec;
+use codec::JsonCodec;
+use codec::RawCodec;
+use delivery::DeliveryHandle;
+use delivery::DeliveryStatus;
+use error::TmsnError;
+use liveness::PeerEvent;
+use liveness::PeerStatus;
 use mock_network::MockNetwork;
 use real_network::RealNetwork;
 use packet::Packet;
+use packet::WireFormat;
 use perfstats::PerfStats;
+use retry::RetryPolicy;
 
 
-type Stream = Vec<(String, BufStream<TcpStream>)>;
+/// Summary of a network shutdown
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// packets (and their destinations) that could not be delivered before the shutdown
+    pub undelivered: Vec<(Option<String>, Packet)>,
+    /// errors occurred while stopping the network threads
+    pub errors: Vec<String>,
+}
+
+impl ShutdownReport {
+    /// Check if all packets were delivered and all threads stopped cleanly
+    pub fn is_clean(&self) -> bool {
+        self.undelivered.is_empty() && self.errors.is_empty()
+    }
+}
+
+
+/// Summary of the connections to the remote machines, returned by `Network::wait_ready`
+#[derive(Clone, Debug, Default)]
+pub struct ReadyReport {
+    /// machines that have subscribed to this machine
+    pub connected: Vec<String>,
+    /// initial remote machines that have not subscribed to this machine yet
+    pub missing: Vec<String>,
+    /// the number of connected machines that was asked for
+    pub min_peers: usize,
+}
+
+impl ReadyReport {
+    /// Check if enough machines are connected
+    pub fn is_ready(&self) -> bool {
+        self.connected.len() >= self.min_peers
+    }
+}
+
+
+type Stream = Vec<network::Subscriber>;
 type LockedStream = Arc<RwLock<Stream>>;
+type BytesCallback = Box<dyn FnMut(String, &[u8]) + Sync + Send>;
+type PacketCallback = Box<dyn FnMut(String, Packet) + Sync + Send>;
+type EventCallback = Arc<dyn Fn(PeerEvent) + Sync + Send>;
+type Outgoing = (Option<String>, Packet);
 const HEAD_NODE: &str = "HEAD_NODE";
 
 /// A structure for communicating over the network in an asynchronous, non-blocking manner
@@ -71,8 +141,9 @@ const HEAD_NODE: &str = "HEAD_NODE";
 ///         *t = Some(msg.clone());
 ///     }),
 ///     false,
-/// );
-/// sleep(Duration::from_millis(100));  // add waiting in case network is not ready
+/// ).unwrap();
+/// // wait for the neighbors to connect
+/// assert!(network.wait_ready(Duration::from_secs(5), neighbors.len()).is_ready());
 ///
 /// // To send out a text message
 /// let message = String::from(MESSAGE);
@@ -83,55 +154,249 @@ const HEAD_NODE: &str = "HEAD_NODE";
 /// sleep(Duration::from_millis(100));
 /// assert_eq!(*(output.read().unwrap()), Some(String::from(MESSAGE)));
 /// ```
-pub enum Network {
-	Real(RealNetwork),
-	Mocked(MockNetwork),
+///
+/// The payloads are encoded as JSON by default. Use `Network::with_codec` to choose
+/// a different `Codec`, e.g. `BincodeCodec` for the large numeric vectors.
+/// All other options of the network can be set with `builder::NetworkBuilder`.
+/// Payloads that are already serialized can be sent with `Network::send_bytes` and received
+/// as byte slices by creating the network with `Network::new_bytes`.
+pub enum Network<C: Codec = JsonCodec> {
+	Real(RealNetwork<C>),
+	Mocked(MockNetwork<C>),
 }
 
 
-impl Network {
-    /// Create a new Network object
+impl Network<JsonCodec> {
+    /// Create a new Network object that encodes the payloads as JSON
     ///
     /// Parameters:
-    ///   * `name` - the local computer name.
-    ///   * `port` - the port number that the machines in the network are listening to.
-    ///   `port` has to be the same value for all machines.
-    ///   * `remote_ips` - a list of IPs to which this computer makes a connection initially.
+    ///   * `port` - the port number that this computer is listening to.
+    ///   * `remote_ips` - a list of addresses to which this computer makes a connection
+    ///     initially, as `host:port`, or as `host` for the computers listening on `port`.
     ///   * `callback` - a callback function to be called when a new packet is received
     ///   * `debug` - set to true to run in the debugging mode (see MockNetwork)
     pub fn new<T: 'static + DeserializeOwned>(
         port: u16,
-        remote_ips: &Vec<String>,
+        remote_ips: &[String],
         callback: Box<dyn FnMut(String, T) + Sync + Send>,
         debug: bool,
-    ) -> Network {
-        if debug {
-            Network::Mocked(MockNetwork::new(port, remote_ips, callback))
-        } else {
-            Network::Real(RealNetwork::new(port, remote_ips, callback))
+    ) -> Result<Network<JsonCodec>, TmsnError> {
+        Network::with_codec(port, remote_ips, callback, debug, JsonCodec, RetryPolicy::default())
+    }
+}
+
+
+impl Network<RawCodec> {
+    /// Create a new Network object that passes the raw payload bytes to the callback
+    ///
+    /// Parameters:
+    ///   * `port` - the port number that this computer is listening to.
+    ///   * `remote_ips` - a list of addresses to which this computer makes a connection
+    ///     initially, as `host:port`, or as `host` for the computers listening on `port`.
+    ///   * `callback` - a callback function to be called with the payload bytes
+    ///     when a new packet is received
+    ///   * `debug` - set to true to run in the debugging mode (see MockNetwork)
+    pub fn new_bytes(
+        port: u16,
+        remote_ips: &[String],
+        callback: BytesCallback,
+        debug: bool,
+    ) -> Result<Network<RawCodec>, TmsnError> {
+        Network::with_bytes_callback(
+            port, remote_ips, callback, debug, RawCodec, RetryPolicy::default())
+    }
+}
+
+
+impl<C: Codec> Network<C> {
+    /// Create a new Network object that encodes the payloads with `codec`
+    ///
+    /// Parameters:
+    ///   * `port` - the port number that this computer is listening to.
+    ///   * `remote_ips` - a list of addresses to which this computer makes a connection
+    ///     initially, as `host:port`, or as `host` for the computers listening on `port`.
+    ///   * `callback` - a callback function to be called when a new packet is received
+    ///   * `debug` - set to true to run in the debugging mode (see MockNetwork)
+    ///   * `codec` - the encoding of the payloads, which has to be the same for all machines
+    ///   * `retry_policy` - how the connections to the remote machines are retried,
+    ///     both initially and after a connection is lost
+    pub fn with_codec<T: 'static + DeserializeOwned>(
+        port: u16,
+        remote_ips: &[String],
+        callback: Box<dyn FnMut(String, T) + Sync + Send>,
+        debug: bool,
+        codec: C,
+        retry_policy: RetryPolicy,
+    ) -> Result<Network<C>, TmsnError> {
+        NetworkBuilder::new(port)
+            .remote_ips(remote_ips)
+            .debug(debug)
+            .codec(codec)
+            .retry_policy(retry_policy)
+            .build(callback)
+    }
+
+    /// Create a new Network object that passes the raw payload bytes to the callback,
+    /// and encodes the payloads given to `Network::send` with `codec`
+    ///
+    /// Parameters are the same as `Network::with_codec`.
+    pub fn with_bytes_callback(
+        port: u16,
+        remote_ips: &[String],
+        callback: BytesCallback,
+        debug: bool,
+        codec: C,
+        retry_policy: RetryPolicy,
+    ) -> Result<Network<C>, TmsnError> {
+        NetworkBuilder::new(port)
+            .remote_ips(remote_ips)
+            .debug(debug)
+            .codec(codec)
+            .retry_policy(retry_policy)
+            .build_bytes(callback)
+    }
+
+    /// Get the address this machine is listening on
+    ///
+    /// If the network was created with port 0, the address has the port assigned by the OS,
+    /// which can be given to the other machines as their remote address.
+    /// Returns `None` in the receive-only and the debug modes, or after the network is shut down.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Network::Real(network) => network.local_addr(),
+            Network::Mocked(_) => None,
         }
     }
 
-    /// Get the list of the address of the subscribed machines
-    pub fn get_subscribers(&mut self) -> Vec<String> {
+    /// Get the list of the node IDs of the subscribed machines
+    ///
+    /// A machine that has not announced a node ID is identified by its address `ip:port`.
+    pub fn get_subscribers(&self) -> Vec<String> {
         match self {
             Network::Real(network) => network.get_subscribers(),
             Network::Mocked(mocked) => mocked.get_subscribers(),
         }
     }
 
+    /// Get the list of the node IDs of the machines this machine is subscribed to
+    pub fn get_subscriptions(&self) -> Vec<String> {
+        match self {
+            Network::Real(network) => network.get_subscriptions(),
+            Network::Mocked(mocked) => mocked.get_subscribers(),
+        }
+    }
+
+    /// Get the liveness of the remote machines this machine has received packets from,
+    /// and the last time a packet arrived from each of them, by their node IDs
+    pub fn peer_status(&self) -> HashMap<String, PeerStatus> {
+        match self {
+            Network::Real(network) => network.peer_status(),
+            Network::Mocked(mocked) => mocked.peer_status(),
+        }
+    }
+
+    /// Get a channel that receives the membership events from now on, i.e. the remote machines
+    /// that connect, disconnect, are suspected to have failed, or recover
+    pub fn peer_events(&self) -> Receiver<PeerEvent> {
+        match self {
+            Network::Real(network) => network.peer_events(),
+            Network::Mocked(mocked) => mocked.peer_events(),
+        }
+    }
+
+    /// Wait until at least `min_peers` of the initial remote machines have subscribed to
+    /// this machine, or `timeout` has passed
+    ///
+    /// The network is created without waiting for the remote machines, so that it can start
+    /// with a partial cluster. The returned report lists the connected machines and
+    /// the initial remote machines that are still missing.
+    pub fn wait_ready(&self, timeout: Duration, min_peers: usize) -> ReadyReport {
+        match self {
+            Network::Real(network) => network.wait_ready(timeout, min_peers),
+            Network::Mocked(mocked) => ReadyReport {
+                connected: mocked.get_subscribers(),
+                missing: vec![],
+                min_peers,
+            },
+        }
+    }
+
     /// Send out a packet
     ///
     /// Parameter:
-    ///     * dest: the address of the destination machine. Set to `None` for broadcasting
+    ///     * dest: the node ID or the address of the destination machine.
+    ///       Set to `None` for broadcasting
     ///     * packet_load: the workload message to be sent out
-    pub fn send<T: Serialize>(&self, dest: Option<String>, packet_load: T) -> Result<(), ()> {
+    ///
+    /// Fails if the payload cannot be encoded, `dest` is not a subscriber,
+    /// or the network has been shut down.
+    /// If the outbound queue is full, it waits for room, fails or drops a packet
+    /// according to the queue policy (see `NetworkBuilder::queue_policy`).
+    pub fn send<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T,
+    ) -> Result<(), TmsnError> {
         match self {
             Network::Real(network) => network.send(dest, packet_load),
             Network::Mocked(mocked) => mocked.send(dest, packet_load),
         }
     }
 
+    /// Send out a packet without waiting for room in the outbound queue
+    ///
+    /// Fails with `TmsnError::WouldBlock` if the queue is full, unless the queue policy
+    /// drops a packet instead (see `NetworkBuilder::queue_policy`).
+    pub fn try_send<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T,
+    ) -> Result<(), TmsnError> {
+        match self {
+            Network::Real(network) => network.try_send(dest, packet_load),
+            Network::Mocked(mocked) => mocked.send(dest, packet_load),
+        }
+    }
+
+    /// Send out a packet, waiting for room in the outbound queue for at most `timeout`
+    ///
+    /// Fails with `TmsnError::Timeout` if the queue is still full by then.
+    pub fn send_timeout<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T, timeout: Duration,
+    ) -> Result<(), TmsnError> {
+        match self {
+            Network::Real(network) => network.send_timeout(dest, packet_load, timeout),
+            Network::Mocked(mocked) => mocked.send(dest, packet_load),
+        }
+    }
+
+    /// Send out a packet to `dest`, and retransmit it until `dest` acknowledges it
+    ///
+    /// The returned handle tells whether the packet was delivered, or failed after
+    /// all retransmissions (see `NetworkBuilder::ack_timeout`).
+    /// The acknowledgements are sent back over the connection from `dest` to this machine,
+    /// so this machine has to be subscribed to `dest`, which has to run a version of tmsn
+    /// that acknowledges the packets by their IDs.
+    /// `dest` might receive the packet more than once if an acknowledgement is lost.
+    pub fn send_reliable<T: Serialize>(
+        &self, dest: String, packet_load: T,
+    ) -> Result<DeliveryHandle, TmsnError> {
+        match self {
+            Network::Real(network) => network.send_reliable(dest, packet_load),
+            Network::Mocked(mocked) => mocked.send(Some(dest), packet_load)
+                .map(|_| DeliveryHandle::new(DeliveryStatus::Delivered)),
+        }
+    }
+
+    /// Send out a packet whose payload is already encoded, bypassing the codec
+    ///
+    /// Parameter:
+    ///     * dest: the node ID or the address of the destination machine.
+    ///       Set to `None` for broadcasting
+    ///     * payload: the payload bytes to be sent out
+    pub fn send_bytes(&self, dest: Option<String>, payload: Vec<u8>) -> Result<(), TmsnError> {
+        match self {
+            Network::Real(network) => network.send_bytes(dest, payload),
+            Network::Mocked(mocked) => mocked.send_bytes(dest, payload),
+        }
+    }
+
     /// Set heartbeat interval
     ///
     /// Parameter:
@@ -144,6 +409,19 @@ impl Network {
         }
     }
 
+    /// Set the encoding of the outgoing packets
+    ///
+    /// Parameter:
+    ///   * wire_format: `WireFormat::Binary` (default) or `WireFormat::Json` for the
+    ///     newline-delimited JSON frames understood by the older versions of tmsn.
+    ///     Incoming packets are accepted in either format.
+    pub fn set_wire_format(&mut self, wire_format: WireFormat) {
+        match self {
+            Network::Real(network) => network.set_wire_format(wire_format),
+            Network::Mocked(_) => {},
+        }
+    }
+
     /// Return a summary of the network communication
     pub fn get_health(&self) -> PerfStats {
         match self {
@@ -152,6 +430,19 @@ impl Network {
         }
     }
 
+    /// Shut down the network
+    ///
+    /// The packets still in the outbound queue are sent out, then all streams are closed,
+    /// the listening port is released, and all network threads are stopped.
+    /// Returns a report of the packets that could not be delivered.
+    /// The network is also shut down when it is dropped.
+    pub fn shutdown(&mut self) -> ShutdownReport {
+        match self {
+            Network::Real(network) => network.shutdown(),
+            Network::Mocked(_) => ShutdownReport::default(),
+        }
+    }
+
     pub fn mock_get(&mut self) -> Result<(Option<String>, Packet), TryRecvError> {
         match self {
             Network::Real(_) => Err(TryRecvError::Empty),
@@ -160,7 +451,7 @@ impl Network {
     }
 
 
-    pub fn mock_send(&mut self, source: &String, packet: Option<String>) {
+    pub fn mock_send(&mut self, source: &str, packet: Option<String>) {
         match self {
             Network::Real(_) => {},
             Network::Mocked(mocked) => mocked.mock_send(source, packet),
@@ -173,13 +464,37 @@ impl Network {
 mod tests {
     extern crate rand;
 
+    use super::HEAD_NODE;
     use super::Network;
+    use builder::NetworkBuilder;
+    use builder::NetworkMode;
+    use codec::BincodeCodec;
+    use codec::RawCodec;
+    use delivery::DeliveryStatus;
+    use error::TmsnError;
+    use liveness::Connection;
+    use liveness::Liveness;
+    use liveness::PeerEvent;
+    use packet::WireFormat;
+    use queue::QueuePolicy;
+    use retry::RetryPolicy;
     use std::fs::File;
     use std::io;
     use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::Shutdown;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
     use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
     use std::thread::sleep;
+    use std::thread::spawn;
     use std::time::Duration;
+    use std::time::Instant;
     use std::sync::Arc;
     use std::sync::RwLock;
     use tests::rand::{thread_rng, Rng};
@@ -187,7 +502,7 @@ mod tests {
 
     static MESSAGE: &str = "Hello, this is a test message.";
 
-    fn test(neighbors: Vec<String>, port: u16) {
+    fn test(neighbors: Vec<String>, port: u16, wire_format: WireFormat) {
         let output: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
         let t = output.clone();
         let mut network = Network::new(
@@ -197,9 +512,10 @@ mod tests {
                 *t = Some(msg.clone());
             }),
             false,
-        );
+        ).unwrap();
         network.set_health_parameter(1);
-        sleep(Duration::from_millis(1000));  // add waiting in case network is not ready
+        network.set_wire_format(wire_format);
+        assert!(network.wait_ready(Duration::from_secs(5), neighbors.len()).is_ready());
 
         // To send out a text message
         let message = String::from(MESSAGE);
@@ -228,7 +544,7 @@ mod tests {
                 *t = Some(msg.clone());
             }),
             false,
-        );
+        ).unwrap();
         network.set_health_parameter(1);
 
         // To send out a text message
@@ -238,7 +554,7 @@ mod tests {
             .collect();
 
         // only scanners send out packets
-        if neighbors.len() == 0 {
+        if neighbors.is_empty() {
             for _ in 0..100 {
                 network.send(None, message.clone()).unwrap();
                 sleep(Duration::from_millis(pkg_interval));
@@ -248,15 +564,963 @@ mod tests {
         }
         let health = network.get_health();
 
-        println!("stress perf,{},local,{}", load_size, health.to_string());
+        println!("stress perf,{},local,{}", load_size, health);
         for (addr, health) in health.others.iter() {
-            println!("stress perf,{},{},{}", load_size, addr, health.to_string());
+            println!("stress perf,{},{},{}", load_size, addr, health);
         }
     }
 
     #[test]
     fn test_local() {
-        test(vec![String::from("127.0.0.1")], 8080);
+        test(vec![String::from("127.0.0.1")], 8080, WireFormat::Binary);
+    }
+
+    #[test]
+    fn test_local_json() {
+        test(vec![String::from("127.0.0.1")], 8079, WireFormat::Json);
+    }
+
+    #[test]
+    fn test_mock_codec() {
+        let output: Arc<RwLock<Vec<Vec<f32>>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let mut network = Network::with_codec(8078, &[],
+            Box::new(move |_s: String, msg: Vec<f32>| {
+                t.write().unwrap().push(msg);
+            }),
+            true,
+            BincodeCodec,
+            RetryPolicy::default(),
+        ).unwrap();
+        let model = vec![0.5f32, -1.25, 3.0];
+        if let Network::Mocked(ref mut mocked) = network {
+            mocked.mock_send(&String::from("mock"), model.clone());
+        }
+        assert_eq!(*output.read().unwrap(), vec![model.clone()]);
+
+        network.send(None, model.clone()).unwrap();
+        let (_, packet) = network.mock_get().unwrap();
+        assert_eq!(packet.content.len(), 8 + 4 * model.len());
+
+        let raw = Network::with_codec(8078, &[],
+            Box::new(move |_s: String, _msg: Vec<u8>| {}),
+            true,
+            RawCodec,
+            RetryPolicy::default(),
+        ).unwrap();
+        raw.send(None, vec![1u8, 2, 3]).unwrap();
+        assert!(raw.send(None, vec![1u32]).is_err());
+        if let Network::Mocked(mut mocked) = raw {
+            let (_, packet) = mocked.mock_get().unwrap();
+            assert_eq!(packet.content, vec![1u8, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_local_bytes() {
+        let output: Arc<RwLock<Vec<Vec<u8>>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let mut network = Network::new_bytes(8077, &[String::from("127.0.0.1")],
+            Box::new(move |_s: String, msg: &[u8]| {
+                t.write().unwrap().push(msg.to_vec());
+            }),
+            false,
+        ).unwrap();
+        sleep(Duration::from_millis(1000));  // add waiting in case network is not ready
+
+        let tensor: Vec<u8> = (0..=255).collect();
+        network.send_bytes(None, tensor.clone()).unwrap();
+        // the payloads that are not valid UTF-8 are sent in the JSON frames too
+        network.set_wire_format(WireFormat::Json);
+        network.send_bytes(None, tensor.clone()).unwrap();
+        sleep(Duration::from_secs(1));
+        assert_eq!(*output.read().unwrap(), vec![tensor.clone(), tensor]);
+    }
+
+    #[test]
+    fn test_evict_subscriber() {
+        let network = Network::new(8076, &[],
+            Box::new(move |_s: String, _msg: String| {}),
+            false,
+        ).unwrap();
+        // the network subscribes back to the new subscriber, i.e. to itself, after waiting
+        // for the hello message that the subscriber does not send
+        let subscriber = TcpStream::connect("127.0.0.1:8076").unwrap();
+        sleep(Duration::from_millis(1500));
+        assert_eq!(network.get_subscribers().len(), 2);
+
+        drop(subscriber);
+        for _ in 0..3 {
+            let _ = network.send(None, String::from(MESSAGE));
+            sleep(Duration::from_millis(200));
+        }
+        assert_eq!(network.get_subscribers().len(), 1);
+        let health = network.get_health();
+        assert_eq!(health.num_disconnects, 1);
+        assert!(health.last_disconnects.contains_key("127.0.0.1:8076"));
+
+        // the same machine can subscribe again
+        let _subscriber = TcpStream::connect("127.0.0.1:8076").unwrap();
+        sleep(Duration::from_millis(1500));
+        assert_eq!(network.get_subscribers().len(), 2);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        for _ in 0..2 {
+            let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+            let t = output.clone();
+            let mut network = Network::new(8075, &[String::from("127.0.0.1")],
+                Box::new(move |_s: String, msg: String| {
+                    t.write().unwrap().push(msg);
+                }),
+                false,
+            ).unwrap();
+            sleep(Duration::from_millis(500));
+            network.send(None, String::from(MESSAGE)).unwrap();
+            let report = network.shutdown();
+            assert!(report.is_clean());
+            assert!(network.send(None, String::from(MESSAGE)).is_err());
+            assert!(network.shutdown().is_clean());
+        }
+        // the port is released
+        drop(TcpListener::bind("0.0.0.0:8075").unwrap());
+
+        // nobody subscribed, so the packet could not be delivered
+        let mut network = Network::new(8075, &[],
+            Box::new(move |_s: String, _msg: String| {}),
+            false,
+        ).unwrap();
+        network.send(None, String::from(MESSAGE)).unwrap();
+        let report = network.shutdown();
+        assert_eq!(report.undelivered.len(), 1);
+        assert_eq!(report.undelivered[0].1.content, b"\"Hello, this is a test message.\"");
+
+        // the packets that a subscriber never read could not be delivered to it
+        let mut network = NetworkBuilder::new(0)
+            .mode(NetworkMode::SendOnly)
+            .write_timeout(Some(Duration::from_millis(200)))
+            .build_bytes(Box::new(|_s: String, _payload: &[u8]| {}))
+            .unwrap();
+        let port = network.local_addr().unwrap().port();
+        let body = format!(r#"{{"port": {}, "version": 1, "min_version": 1}}"#, port);
+        let mut stuck = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stuck.write_all(&[0x5A]).unwrap();
+        stuck.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+        stuck.write_all(body.as_bytes()).unwrap();
+        assert!(network.wait_ready(Duration::from_secs(5), 1).is_ready());
+        for _ in 0..64 {
+            network.send_bytes(None, vec![0; 1 << 18]).unwrap();
+        }
+        let report = network.shutdown();
+        assert!(!report.undelivered.is_empty());
+        let name = format!("127.0.0.1:{}", port);
+        for (dest, packet) in report.undelivered {
+            assert_eq!(dest, Some(name.clone()));
+            assert_eq!(packet.content.len(), 1 << 18);
+        }
+
+        // a remote machine that never answers does not hold up the shutdown,
+        // as a listener with a full backlog drops the new connections silently
+        let backlog = socket2::Socket::new(
+            socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        backlog.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+        backlog.listen(0).unwrap();
+        let addr = backlog.local_addr().unwrap().as_socket().unwrap();
+        let _queued: Vec<_> = (0..4)
+            .filter_map(|_| TcpStream::connect_timeout(&addr, Duration::from_millis(200)).ok())
+            .collect();
+        let mut network = NetworkBuilder::new(0)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[addr.to_string()])
+            .build_bytes(Box::new(|_s: String, _payload: &[u8]| {}))
+            .unwrap();
+        sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        assert!(network.shutdown().is_clean());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        let network = Network::new(8072, &[],
+            Box::new(move |_s: String, _msg: String| {}),
+            false,
+        ).unwrap();
+        let report = network.wait_ready(Duration::from_millis(200), 1);
+        assert!(!report.is_ready());
+        assert!(report.connected.is_empty());
+        assert!(report.missing.is_empty());
+
+        let neighbors = vec![String::from("127.0.0.1")];
+        let network = Network::new(8071, &neighbors,
+            Box::new(move |_s: String, _msg: String| {}),
+            false,
+        ).unwrap();
+        let report = network.wait_ready(Duration::from_secs(5), 1);
+        assert!(report.is_ready());
+        assert!(report.connected.contains(&String::from("127.0.0.1:8071")));
+        assert!(report.missing.is_empty());
+        // not enough machines in the cluster
+        let start = Instant::now();
+        assert!(!network.wait_ready(Duration::from_millis(200), 3).is_ready());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_builder() {
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        let invalid = vec![
+            NetworkBuilder::new(0).remote_ips(&[String::from("127.0.0.1")]),
+            NetworkBuilder::new(8069).heartbeat_interval_secs(0),
+            NetworkBuilder::new(8069)
+                .retry_policy(RetryPolicy { jitter: 2.0, ..RetryPolicy::default() }),
+            NetworkBuilder::new(8069).node_id(""),
+            NetworkBuilder::new(8069).head_node(""),
+            NetworkBuilder::new(8069).queue_capacity(0),
+            NetworkBuilder::new(8069).peer_queue_capacity(0),
+            NetworkBuilder::new(8069).read_timeout(Some(Duration::from_secs(0))),
+            NetworkBuilder::new(8069).reorder_window(0),
+            NetworkBuilder::new(8069).reorder_timeout(Duration::from_secs(0)),
+            NetworkBuilder::new(8069).peer_queue_capacity(16).replay_capacity(32),
+            NetworkBuilder::new(8069).replay_retention(Duration::from_secs(0)),
+            NetworkBuilder::new(8069).suspect_timeout(Duration::from_secs(200)),
+        ];
+        for builder in invalid {
+            match builder.build(callback()) {
+                Err(TmsnError::InvalidConfig(_)) => {},
+                _ => panic!("Invalid configuration should be rejected"),
+            }
+        }
+        match NetworkBuilder::new(8069).remote_ips(&[String::from("head:")]).build(callback()) {
+            Err(TmsnError::InvalidAddress(addr)) => assert_eq!(addr, "head:"),
+            _ => panic!("Invalid remote address should be rejected"),
+        }
+        match NetworkBuilder::new(8069).debug(true).codec(BincodeCodec).build(callback()) {
+            Ok(Network::Mocked(_)) => {},
+            _ => panic!("Debug mode should build a mocked network"),
+        }
+
+        let neighbors = vec![String::from("127.0.0.1")];
+        let network = NetworkBuilder::new(8070)
+            .remote_ips(&neighbors)
+            .mode(NetworkMode::OneWay)
+            .bind_addr("127.0.0.1".parse().unwrap())
+            .heartbeat_interval_secs(1)
+            .build(callback())
+            .unwrap();
+        assert!(network.wait_ready(Duration::from_secs(5), 1).is_ready());
+        sleep(Duration::from_millis(1500));
+        // one-way, so the network does not subscribe back to its subscriber
+        assert_eq!(network.get_subscribers(), vec![String::from("127.0.0.1:8070")]);
+        assert!(network.get_health().num_hb > 0);
+
+        // the packets to `HEAD_NODE` go to the head node, rather than the first subscriber
+        let publisher = NetworkBuilder::new(0)
+            .mode(NetworkMode::SendOnly)
+            .head_node("beta")
+            .build(callback())
+            .unwrap();
+        let remote_ips = vec![format!("127.0.0.1:{}", publisher.local_addr().unwrap().port())];
+        let received = Arc::new(RwLock::new(vec![]));
+        let subscribers: Vec<_> = ["alpha", "beta"].iter().map(|node_id| {
+            let r = received.clone();
+            let subscriber = NetworkBuilder::new(0)
+                .mode(NetworkMode::ReceiveOnly)
+                .node_id(node_id)
+                .remote_ips(&remote_ips)
+                .build(Box::new(move |_s: String, msg: String| {
+                    r.write().unwrap().push((node_id.to_string(), msg));
+                }))
+                .unwrap();
+            assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+            subscriber
+        }).collect();
+        assert!(publisher.wait_ready(Duration::from_secs(5), 2).is_ready());
+        assert_eq!(publisher.get_subscribers(), vec!["alpha", "beta"]);
+        publisher.send(Some(String::from(HEAD_NODE)), String::from(MESSAGE)).unwrap();
+        sleep(Duration::from_millis(500));
+        assert_eq!(*received.read().unwrap(), vec![(String::from("beta"), String::from(MESSAGE))]);
+        drop(subscribers);
+    }
+
+    #[test]
+    fn test_cluster_on_one_host() {
+        let ports = [8065, 8066, 8067];
+        let outputs: Vec<Arc<RwLock<Vec<String>>>> =
+            ports.iter().map(|_| Arc::new(RwLock::new(vec![]))).collect();
+        let networks: Vec<Network> = ports.iter().enumerate().map(|(index, port)| {
+            // every node subscribes to the nodes started before it
+            let remote_addrs: Vec<String> = ports[..index].iter()
+                .map(|port| format!("127.0.0.1:{}", port))
+                .collect();
+            let t = outputs[index].clone();
+            Network::new(*port, &remote_addrs,
+                Box::new(move |sender: String, _msg: String| {
+                    t.write().unwrap().push(sender);
+                }),
+                false,
+            ).unwrap()
+        }).collect();
+        // the nodes started earlier learn the ports of the later nodes, and subscribe back
+        for network in networks.iter() {
+            assert!(network.wait_ready(Duration::from_secs(5), 2).is_ready());
+        }
+        sleep(Duration::from_millis(500));
+        let mut subscriptions = networks[0].get_subscriptions();
+        subscriptions.sort();
+        assert_eq!(subscriptions, vec!["127.0.0.1:8066", "127.0.0.1:8067"]);
+
+        networks[0].send(None, String::from(MESSAGE)).unwrap();
+        networks[0].send(Some(String::from("127.0.0.1:8067")), String::from(MESSAGE)).unwrap();
+        sleep(Duration::from_millis(500));
+        assert_eq!(*outputs[1].read().unwrap(), vec!["127.0.0.1:8065"]);
+        assert_eq!(*outputs[2].read().unwrap(), vec!["127.0.0.1:8065", "127.0.0.1:8065"]);
+    }
+
+    #[test]
+    fn test_node_ids() {
+        let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let alpha = NetworkBuilder::new(8064)
+            .node_id("alpha")
+            .build(Box::new(move |sender: String, _msg: String| {
+                t.write().unwrap().push(sender);
+            }))
+            .unwrap();
+        let beta = NetworkBuilder::new(8063)
+            .node_id("beta")
+            .remote_ips(&[String::from("127.0.0.1:8064")])
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        assert!(alpha.wait_ready(Duration::from_secs(5), 1).is_ready());
+        assert!(beta.wait_ready(Duration::from_secs(5), 1).is_ready());
+        // alpha subscribes back to beta
+        sleep(Duration::from_millis(500));
+        let report = beta.wait_ready(Duration::from_secs(5), 1);
+        assert!(report.missing.is_empty());
+        assert_eq!(report.connected, vec!["alpha"]);
+        assert_eq!(alpha.get_subscribers(), vec!["beta"]);
+        assert_eq!(alpha.get_subscriptions(), vec!["beta"]);
+
+        // the destination is given either by its node ID or by its address
+        beta.send(Some(String::from("alpha")), String::from(MESSAGE)).unwrap();
+        beta.send(Some(String::from("127.0.0.1:8064")), String::from(MESSAGE)).unwrap();
+        sleep(Duration::from_millis(500));
+        assert_eq!(*output.read().unwrap(), vec!["beta", "beta"]);
+        assert!(alpha.get_health().others.contains_key("beta"));
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        let network = Network::new(8062, &[], callback(), false).unwrap();
+        let hello = |body: &str| {
+            let mut stream = TcpStream::connect("127.0.0.1:8062").unwrap();
+            stream.write_all(&[0x5A]).unwrap();
+            stream.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(body.as_bytes()).unwrap();
+            stream
+        };
+
+        // the network replies with its own hello message, then closes the connection
+        let mut incompatible = hello(r#"{"node_id": "future", "version": 9, "min_version": 8}"#);
+        let mut reply = vec![];
+        incompatible.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply[0], 0x5A);
+        assert!(String::from_utf8_lossy(&reply[5..]).contains(r#""version":1"#));
+        assert!(network.get_subscribers().is_empty());
+
+        let _compatible = hello(r#"{"node_id": "past", "version": 1, "min_version": 0}"#);
+        sleep(Duration::from_millis(500));
+        assert_eq!(network.get_subscribers(), vec!["past"]);
+
+        // a machine on protocol version 0 only understands the JSON frames
+        let mut legacy = hello(r#"{"node_id": "ancient", "version": 0, "min_version": 0}"#);
+        legacy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut header = [0u8; 5];
+        legacy.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x5A);
+        let mut body = vec![0u8; u32::from_be_bytes([header[1], header[2], header[3], header[4]])
+            as usize];
+        legacy.read_exact(&mut body).unwrap();
+        sleep(Duration::from_millis(500));
+        network.send(Some(String::from("ancient")), String::from("hello")).unwrap();
+        let mut frame = String::new();
+        BufReader::new(legacy).read_line(&mut frame).unwrap();
+        assert!(frame.starts_with('['));
+        assert!(frame.contains("hello"));
+
+        // an incompatible machine is not subscribed to again
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _subscriber = NetworkBuilder::new(0)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[listener.local_addr().unwrap().to_string()])
+            .retry_policy(RetryPolicy {
+                initial_delay: Duration::from_millis(100), ..RetryPolicy::default() })
+            .build(callback())
+            .unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let body = r#"{"node_id": "future", "version": 9, "min_version": 8}"#;
+        let mut num_accepted = 0;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(1500) {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    num_accepted += 1;
+                    stream.write_all(&[0x5A]).unwrap();
+                    stream.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+                    stream.write_all(body.as_bytes()).unwrap();
+                },
+                Err(_) => sleep(Duration::from_millis(10)),
+            }
+        }
+        assert_eq!(num_accepted, 1);
+    }
+
+    #[test]
+    fn test_addresses() {
+        // the host name is resolved when connecting
+        let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let _server = Network::new(8061, &[], Box::new(|_s: String, _msg: String| {}), false)
+            .unwrap();
+        let client = Network::new(8060, &[String::from("localhost:8061")],
+            Box::new(move |sender: String, _msg: String| {
+                t.write().unwrap().push(sender);
+            }),
+            false,
+        ).unwrap();
+        assert!(client.wait_ready(Duration::from_secs(5), 1).is_ready());
+        // the server subscribes back by IP, which is the same machine
+        sleep(Duration::from_millis(500));
+        let report = client.wait_ready(Duration::from_secs(5), 1);
+        assert!(report.missing.is_empty());
+        assert_eq!(report.connected, vec!["127.0.0.1:8061"]);
+        assert_eq!(client.get_subscriptions(), vec!["127.0.0.1:8061"]);
+    }
+
+    #[test]
+    fn test_ephemeral_port() {
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        let first = Network::new(0, &[], callback(), false).unwrap();
+        let first_addr = first.local_addr().unwrap();
+        assert_ne!(first_addr.port(), 0);
+        let remote_addrs = vec![format!("127.0.0.1:{}", first_addr.port())];
+        let second = Network::new(0, &remote_addrs, callback(), false).unwrap();
+        let second_addr = second.local_addr().unwrap();
+        assert_ne!(second_addr.port(), first_addr.port());
+
+        // the assigned port is announced, so the first machine subscribes back to it
+        let report = second.wait_ready(Duration::from_secs(5), 1);
+        assert!(report.is_ready());
+        sleep(Duration::from_millis(500));
+        assert_eq!(first.get_subscriptions(),
+                   vec![format!("127.0.0.1:{}", second_addr.port())]);
+        assert_eq!(second.get_subscriptions(), remote_addrs);
+    }
+
+    #[test]
+    fn test_outbound_queue() {
+        // nobody subscribes, so the packets stay in the queue
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        let mut network = NetworkBuilder::new(8059)
+            .queue_capacity(2)
+            .queue_policy(QueuePolicy::WouldBlock)
+            .build(callback())
+            .unwrap();
+        network.send(None, String::from(MESSAGE)).unwrap();
+        network.send(None, String::from(MESSAGE)).unwrap();
+        match network.send(None, String::from(MESSAGE)) {
+            Err(TmsnError::WouldBlock) => {},
+            _ => panic!("Sending to a full queue should fail"),
+        }
+        assert_eq!(network.shutdown().undelivered.len(), 2);
+
+        let mut network = NetworkBuilder::new(8058)
+            .queue_capacity(1)
+            .build(callback())
+            .unwrap();
+        network.send(None, String::from(MESSAGE)).unwrap();
+        match network.try_send(None, String::from(MESSAGE)) {
+            Err(TmsnError::WouldBlock) => {},
+            _ => panic!("Trying to send to a full queue should fail"),
+        }
+        let start = Instant::now();
+        match network.send_timeout(None, String::from(MESSAGE), Duration::from_millis(200)) {
+            Err(TmsnError::Timeout) => assert!(start.elapsed() >= Duration::from_millis(200)),
+            _ => panic!("Sending to a full queue should time out"),
+        }
+        assert_eq!(network.shutdown().undelivered.len(), 1);
+    }
+
+    #[test]
+    fn test_receipts() {
+        let mut network = NetworkBuilder::new(0)
+            .mode(NetworkMode::SendOnly)
+            .build_bytes(Box::new(|_s: String, _payload: &[u8]| {}))
+            .unwrap();
+        let remote_ips = vec![format!("127.0.0.1:{}", network.local_addr().unwrap().port())];
+        let received = Arc::new(RwLock::new(0));
+        let receive_only = {
+            let r = received.clone();
+            NetworkBuilder::new(0)
+                .mode(NetworkMode::ReceiveOnly)
+                .node_id("receive-only")
+                .remote_ips(&remote_ips)
+                .build_bytes(Box::new(move |_s: String, _payload: &[u8]| {
+                    *r.write().unwrap() += 1;
+                }))
+                .unwrap()
+        };
+        // the publisher does not subscribe back, so nothing would take out the receipts
+        let one_way = {
+            let r = received.clone();
+            NetworkBuilder::new(0)
+                .mode(NetworkMode::OneWay)
+                .node_id("one-way")
+                .remote_ips(&remote_ips)
+                .build_bytes(Box::new(move |_s: String, _payload: &[u8]| {
+                    *r.write().unwrap() += 1;
+                }))
+                .unwrap()
+        };
+        assert!(network.wait_ready(Duration::from_secs(5), 2).is_ready());
+        for _ in 0..2000 {
+            network.send_bytes(None, vec![0; 16]).unwrap();
+        }
+        let start = Instant::now();
+        while *received.read().unwrap() < 4000 && start.elapsed() < Duration::from_secs(10) {
+            sleep(Duration::from_millis(50));
+        }
+        assert_eq!(*received.read().unwrap(), 4000);
+        assert_eq!(receive_only.get_health().outbound_depth, 0);
+        assert_eq!(one_way.get_health().outbound_depth, 0);
+        assert!(network.shutdown().is_clean());
+    }
+
+    #[test]
+    fn test_slow_peer() {
+        let mut network = NetworkBuilder::new(8057)
+            .mode(NetworkMode::SendOnly)
+            .peer_queue_capacity(16)
+            .build_bytes(Box::new(|_s: String, _payload: &[u8]| {}))
+            .unwrap();
+        // the slow peer never reads, and is named by its address since it has no node ID
+        let body = r#"{"port": 8057, "version": 1, "min_version": 1}"#;
+        let mut slow = TcpStream::connect("127.0.0.1:8057").unwrap();
+        slow.write_all(&[0x5A]).unwrap();
+        slow.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+        slow.write_all(body.as_bytes()).unwrap();
+        sleep(Duration::from_millis(1500));
+        let received = Arc::new(RwLock::new(0));
+        let r = received.clone();
+        let fast = NetworkBuilder::new(8057)
+            .mode(NetworkMode::ReceiveOnly)
+            .node_id("fast")
+            .remote_ips(&[String::from("127.0.0.1")])
+            .build_bytes(Box::new(move |_s: String, _payload: &[u8]| {
+                *r.write().unwrap() += 1;
+            }))
+            .unwrap();
+        assert!(network.wait_ready(Duration::from_secs(5), 2).is_ready());
+
+        // the slow peer falls behind once its socket buffers are full
+        for _ in 0..200 {
+            network.send_bytes(None, vec![0; 1 << 18]).unwrap();
+            sleep(Duration::from_millis(2));
+        }
+        sleep(Duration::from_millis(1000));
+        assert_eq!(*received.read().unwrap(), 200);
+        assert_eq!(network.get_subscribers(), vec![String::from("fast")]);
+        let health = network.get_health();
+        assert_eq!(health.num_disconnects, 1);
+        assert!(health.last_disconnects.contains_key("127.0.0.1:8057"));
+        assert_eq!(health.queue_depths.get("fast"), Some(&0));
+        drop(fast);
+        assert!(network.shutdown().is_clean());
+    }
+
+    #[test]
+    fn test_socket_timeouts() {
+        // a remote machine that accepts the connections, but never sends anything
+        let listener = TcpListener::bind("127.0.0.1:8056").unwrap();
+        let network = NetworkBuilder::new(8056)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[String::from("127.0.0.1")])
+            .read_timeout(Some(Duration::from_secs(1)))
+            .keepalive(None)
+            .nodelay(true)
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let (_first, _) = listener.accept().unwrap();
+        // the network waits for the hello reply first
+        sleep(Duration::from_millis(1500));
+        assert_eq!(network.get_subscriptions(), vec![String::from("127.0.0.1:8056")]);
+        // the connection is considered lost after the read timeout, and connected again
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.accept().is_err() {
+            assert!(Instant::now() < deadline, "The lost connection should be reconnected");
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_reliable_send() {
+        let received = Arc::new(RwLock::new(vec![]));
+        let r = received.clone();
+        let alpha = NetworkBuilder::new(8055)
+            .node_id("alpha")
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let beta = NetworkBuilder::new(8054)
+            .node_id("beta")
+            .remote_ips(&[String::from("127.0.0.1:8055")])
+            .build(Box::new(move |_s: String, msg: String| r.write().unwrap().push(msg)))
+            .unwrap();
+        // the acknowledgements come back once alpha subscribes back to beta
+        assert!(alpha.wait_ready(Duration::from_secs(5), 1).is_ready());
+        assert!(beta.wait_ready(Duration::from_secs(5), 1).is_ready());
+        let handle = alpha.send_reliable(String::from("beta"), String::from(MESSAGE)).unwrap();
+        assert_eq!(handle.wait(Duration::from_secs(5)), DeliveryStatus::Delivered);
+        assert_eq!(*received.read().unwrap(), vec![String::from(MESSAGE)]);
+        match alpha.send_reliable(String::from("gamma"), String::from(MESSAGE)) {
+            Err(TmsnError::UnknownPeer(_)) => {},
+            _ => panic!("Sending to an unknown machine should fail"),
+        }
+
+        // a send-only network never receives the acknowledgements
+        let mut network = NetworkBuilder::new(8053)
+            .mode(NetworkMode::SendOnly)
+            .ack_timeout(Duration::from_millis(200))
+            .max_retransmits(2)
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let _subscriber = TcpStream::connect("127.0.0.1:8053").unwrap();
+        assert!(network.wait_ready(Duration::from_secs(5), 1).is_ready());
+        let start = Instant::now();
+        let handle = network.send_reliable(
+            String::from("127.0.0.1:8053"), String::from(MESSAGE)).unwrap();
+        assert_eq!(handle.status(), DeliveryStatus::Pending);
+        assert_eq!(handle.wait(Duration::from_secs(5)), DeliveryStatus::Failed);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+        let handle = network.send_reliable(
+            String::from("127.0.0.1:8053"), String::from(MESSAGE)).unwrap();
+        network.shutdown();
+        assert_eq!(handle.status(), DeliveryStatus::Failed);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let publisher = NetworkBuilder::new(8052)
+            .mode(NetworkMode::SendOnly)
+            .node_id("publisher")
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let subscriber = NetworkBuilder::new(8052)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[String::from("127.0.0.1")])
+            .reorder_window(16)
+            .build(Box::new(move |_s: String, msg: String| t.write().unwrap().push(msg)))
+            .unwrap();
+        assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+        assert!(publisher.wait_ready(Duration::from_secs(5), 1).is_ready());
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        for msg in expected.iter() {
+            publisher.send(None, msg.clone()).unwrap();
+        }
+        sleep(Duration::from_millis(500));
+        assert_eq!(*output.read().unwrap(), expected);
+        let health = subscriber.get_health();
+        assert_eq!(health.num_lost.get("publisher"), Some(&0));
+        assert_eq!(health.num_duplicates.get("publisher"), Some(&0));
+        assert_eq!(health.num_reordered.get("publisher"), Some(&0));
+
+        // a missing packet is waited for at most `reorder_timeout`, even if nothing else arrives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let received = Arc::new(RwLock::new(vec![]));
+        let r = received.clone();
+        let _subscriber = NetworkBuilder::new(0)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[listener.local_addr().unwrap().to_string()])
+            .reorder_window(16)
+            .reorder_timeout(Duration::from_millis(300))
+            .build_bytes(Box::new(move |_s: String, payload: &[u8]| {
+                r.write().unwrap().push(payload[0]);
+            }))
+            .unwrap();
+        let (mut publisher, _) = listener.accept().unwrap();
+        let hello = r#"{"version": 1, "min_version": 1, "features": ["seq"], "session": 1}"#;
+        publisher.write_all(&[0x5A]).unwrap();
+        publisher.write_all(&(hello.len() as u32).to_be_bytes()).unwrap();
+        publisher.write_all(hello.as_bytes()).unwrap();
+        for seq in [0u32, 2] {
+            // | 0xA6 | payload length | message | index | sent time | sequence number | payload |
+            publisher.write_all(&[0xA6, 0, 0, 0, 1, 0]).unwrap();
+            publisher.write_all(&seq.to_be_bytes()).unwrap();
+            publisher.write_all(&[0; 8]).unwrap();
+            publisher.write_all(&seq.to_be_bytes()).unwrap();
+            publisher.write_all(&[seq as u8]).unwrap();
+        }
+        sleep(Duration::from_millis(150));
+        assert_eq!(*received.read().unwrap(), vec![0]);
+        sleep(Duration::from_millis(400));
+        assert_eq!(*received.read().unwrap(), vec![0, 2]);
+    }
+
+    // Forward the connections to a port assigned by the OS to `port`, and return the port.
+    // The connections are closed, and the new connections are refused, while the returned flag
+    // is set.
+    fn start_proxy(port: u16) -> (u16, Arc<AtomicBool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let paused = Arc::new(AtomicBool::new(false));
+        let p = paused.clone();
+        spawn(move || for downstream in listener.incoming() {
+            let downstream = downstream.unwrap();
+            if p.load(Ordering::SeqCst) {
+                continue;
+            }
+            let upstream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let streams = [(downstream.try_clone().unwrap(), upstream.try_clone().unwrap()),
+                           (upstream, downstream)];
+            for (mut from, mut to) in streams {
+                let p = p.clone();
+                spawn(move || {
+                    from.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+                    let mut buf = [0u8; 4096];
+                    while !p.load(Ordering::SeqCst) {
+                        match from.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(len) => if to.write_all(&buf[..len]).is_err() {
+                                break;
+                            },
+                            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                            Err(_) => break,
+                        }
+                    }
+                    let _ = from.shutdown(Shutdown::Both);
+                    let _ = to.shutdown(Shutdown::Both);
+                });
+            }
+        });
+        (proxy_port, paused)
+    }
+
+    #[test]
+    fn test_replay() {
+        // the packets are kept up to a number of packets or of bytes. The subscriber without
+        // a node ID is named by the address of its connection, so its packets are not kept
+        // while it is disconnected. Neither are the packets of a subscriber gone for too long.
+        // The packets are numbered anew once either reconnects.
+        let retention = Duration::from_secs(60);
+        let cases = [
+            (1024, 1 << 20, retention, Some("subscriber"), 5, 0),
+            (2, 1 << 20, retention, Some("subscriber"), 2, 3),
+            (1024, 50, retention, Some("subscriber"), 2, 3),
+            (1024, 1 << 20, retention, None, 0, 0),
+            (1024, 1 << 20, Duration::from_millis(300), Some("subscriber"), 0, 0),
+        ];
+        for (capacity, max_bytes, retention, node_id, num_replayed, num_lost) in cases {
+            let publisher = NetworkBuilder::new(0)
+                .mode(NetworkMode::SendOnly)
+                .node_id("publisher")
+                .replay_capacity(capacity)
+                .replay_max_bytes(max_bytes)
+                .replay_retention(retention)
+                .build(Box::new(move |_s: String, _msg: String| {}))
+                .unwrap();
+            let (port, paused) = start_proxy(publisher.local_addr().unwrap().port());
+            let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+            let t = output.clone();
+            let mut subscriber = NetworkBuilder::new(0)
+                .mode(NetworkMode::ReceiveOnly)
+                .remote_ips(&[format!("127.0.0.1:{}", port)])
+                .retry_policy(RetryPolicy {
+                    initial_delay: Duration::from_millis(100), ..RetryPolicy::default() });
+            if let Some(node_id) = node_id {
+                subscriber = subscriber.node_id(node_id);
+            }
+            let subscriber = subscriber
+                .build(Box::new(move |_s: String, msg: String| t.write().unwrap().push(msg)))
+                .unwrap();
+            assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+            assert!(publisher.wait_ready(Duration::from_secs(5), 1).is_ready());
+            for i in 0..5 {
+                publisher.send(None, i.to_string()).unwrap();
+            }
+            sleep(Duration::from_millis(500));
+
+            // the packets sent while the subscriber is disconnected are replayed once it
+            // reconnects, as long as the publisher still keeps them
+            paused.store(true, Ordering::SeqCst);
+            sleep(Duration::from_millis(500));
+            for i in 5..10 {
+                publisher.send(None, i.to_string()).unwrap();
+            }
+            sleep(Duration::from_millis(500));
+            paused.store(false, Ordering::SeqCst);
+            sleep(Duration::from_millis(1500));
+            for i in 10..15 {
+                publisher.send(None, i.to_string()).unwrap();
+            }
+            sleep(Duration::from_millis(500));
+            let expected: Vec<String> = (0..15)
+                .filter(|i| *i < 5 || *i >= 10 - num_replayed)
+                .map(|i| i.to_string())
+                .collect();
+            assert_eq!(*output.read().unwrap(), expected);
+            let health = subscriber.get_health();
+            assert_eq!(health.num_lost.get("publisher"), Some(&num_lost));
+            assert_eq!(health.num_duplicates.get("publisher"), Some(&0));
+        }
+    }
+
+    #[test]
+    fn test_peer_status() {
+        let mut publisher = NetworkBuilder::new(8047)
+            .mode(NetworkMode::SendOnly)
+            .node_id("publisher")
+            .heartbeat_interval_secs(1)
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let subscriber = NetworkBuilder::new(8047)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[String::from("127.0.0.1")])
+            .suspect_timeout(Duration::from_millis(1500))
+            .dead_timeout(Duration::from_secs(5))
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+        // the heartbeats keep the idle publisher alive
+        sleep(Duration::from_millis(2500));
+        let status = subscriber.peer_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status["publisher"].liveness, Liveness::Alive);
+
+        publisher.shutdown();
+        sleep(Duration::from_secs(2));
+        let last_seen = subscriber.peer_status()["publisher"].last_seen;
+        assert_eq!(subscriber.peer_status()["publisher"].liveness, Liveness::Suspect);
+        sleep(Duration::from_millis(3500));
+        let status = subscriber.peer_status();
+        assert_eq!(status["publisher"].liveness, Liveness::Dead);
+        assert_eq!(status["publisher"].last_seen, last_seen);
+    }
+
+    #[test]
+    fn test_peer_events() {
+        let publisher = || NetworkBuilder::new(8046)
+            .mode(NetworkMode::SendOnly)
+            .node_id("publisher")
+            .heartbeat_interval_secs(1)
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let mut network = publisher();
+        let publisher_events = network.peer_events();
+        let subscriber = NetworkBuilder::new(8046)
+            .mode(NetworkMode::ReceiveOnly)
+            .node_id("subscriber")
+            .remote_ips(&[String::from("127.0.0.1")])
+            .suspect_timeout(Duration::from_millis(1500))
+            .retry_policy(RetryPolicy {
+                initial_delay: Duration::from_millis(100), backoff_factor: 1.0,
+                max_attempts: None, ..RetryPolicy::default() })
+            .build(Box::new(move |_s: String, _msg: String| {}))
+            .unwrap();
+        let events = subscriber.peer_events();
+        let subscriber_name = String::from("subscriber");
+        assert_eq!(publisher_events.recv_timeout(Duration::from_secs(5)),
+                   Ok(PeerEvent::PeerConnected(subscriber_name, Connection::Subscriber)));
+        assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+        sleep(Duration::from_millis(1500));
+
+        // the subscriber loses the connection, then suspects the silent publisher
+        network.shutdown();
+        let publisher_name = String::from("publisher");
+        let next_event = || loop {
+            match events.recv_timeout(Duration::from_secs(5)) {
+                Ok(PeerEvent::PeerConnected(_, _)) => {},
+                event => return event.unwrap(),
+            }
+        };
+        assert_eq!(next_event(),
+                   PeerEvent::PeerDisconnected(publisher_name.clone(), Connection::Subscription));
+        assert_eq!(next_event(), PeerEvent::PeerSuspected(publisher_name.clone()));
+
+        // the publisher recovers once it is back
+        let _network = publisher();
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)),
+                   Ok(PeerEvent::PeerConnected(publisher_name.clone(), Connection::Subscription)));
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)),
+                   Ok(PeerEvent::PeerRecovered(publisher_name)));
+    }
+
+    #[test]
+    fn test_modes() {
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        match NetworkBuilder::new(8068)
+                .mode(NetworkMode::SendOnly)
+                .remote_ips(&[String::from("127.0.0.1")])
+                .build(callback()) {
+            Err(TmsnError::InvalidConfig(_)) => {},
+            _ => panic!("A send-only network cannot subscribe to the remote machines"),
+        }
+
+        let publisher = NetworkBuilder::new(8068)
+            .mode(NetworkMode::SendOnly)
+            .build(callback())
+            .unwrap();
+        // the receive-only network does not listen on the port, so both can run on one machine
+        let output: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(vec![]));
+        let t = output.clone();
+        let subscriber = NetworkBuilder::new(8068)
+            .mode(NetworkMode::ReceiveOnly)
+            .remote_ips(&[String::from("127.0.0.1")])
+            .build(Box::new(move |_s: String, msg: String| {
+                t.write().unwrap().push(msg);
+            }))
+            .unwrap();
+        assert!(subscriber.wait_ready(Duration::from_secs(5), 1).is_ready());
+        assert!(publisher.wait_ready(Duration::from_secs(5), 1).is_ready());
+        // the publisher does not subscribe back
+        assert!(publisher.get_subscriptions().is_empty());
+
+        publisher.send(None, String::from(MESSAGE)).unwrap();
+        sleep(Duration::from_millis(500));
+        assert_eq!(*output.read().unwrap(), vec![String::from(MESSAGE)]);
+        match subscriber.send(None, String::from(MESSAGE)) {
+            Err(TmsnError::Unsupported(_)) => {},
+            _ => panic!("A receive-only network cannot send"),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let callback = || Box::new(move |_s: String, _msg: String| {});
+        let _listener = TcpListener::bind("0.0.0.0:8074").unwrap();
+        match Network::new(8074, &[], callback(), false) {
+            Err(TmsnError::Bind(_, _)) => {},
+            _ => panic!("Binding a port in use should fail"),
+        }
+        match Network::new(8073, &[String::from("not an ip")], callback(), false) {
+            Err(TmsnError::InvalidAddress(addr)) => assert_eq!(addr, "not an ip"),
+            _ => panic!("Parsing an invalid address should fail"),
+        }
+
+        let mut network = Network::new(8073, &[], callback(), false).unwrap();
+        match network.send(Some(String::from("10.0.0.1")), String::from(MESSAGE)) {
+            Err(TmsnError::UnknownPeer(peer)) => assert_eq!(peer, "10.0.0.1:8073"),
+            _ => panic!("Sending to an unknown peer should fail"),
+        }
+        network.shutdown();
+        match network.send(None, String::from(MESSAGE)) {
+            Err(TmsnError::Closed) => {},
+            _ => panic!("Sending after the shutdown should fail"),
+        }
     }
 
     #[test]
@@ -265,11 +1529,11 @@ mod tests {
         if let Ok(lines) = read_lines("./neighbors.txt") {
             lines.for_each(|line| {
                 let addr = line.unwrap();
-                if addr.trim().len() > 0 {
+                if !addr.trim().is_empty() {
                     neighbors.push(addr.to_string());
                 }
             });
-            test(neighbors, 8081);
+            test(neighbors, 8081, WireFormat::Binary);
         }
     }
 
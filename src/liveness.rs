@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use network::read_lock;
+use network::write_lock;
+
+
+/// Liveness of a remote machine, judged by the time since the last packet from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    /// a packet arrived within `suspect_timeout`
+    Alive,
+    /// no packet arrived within `suspect_timeout`, but one did within `dead_timeout`
+    Suspect,
+    /// no packet arrived within `dead_timeout`
+    Dead,
+}
+
+
+/// Liveness of a remote machine, and the last time a packet arrived from it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerStatus {
+    /// how recently a packet arrived from the remote machine
+    pub liveness: Liveness,
+    /// the last time any packet, heartbeats included, arrived from the remote machine
+    pub last_seen: SystemTime,
+}
+
+
+/// Connection to a remote machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connection {
+    /// the remote machine subscribed to this machine, and receives the packets it sends out
+    Subscriber,
+    /// this machine subscribed to the remote machine, and receives the packets it sends out
+    Subscription,
+}
+
+
+/// Change in the membership of the network, with the node ID of the remote machine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// a connection to the remote machine was set up
+    PeerConnected(String, Connection),
+    /// a connection to the remote machine was lost
+    PeerDisconnected(String, Connection),
+    /// no packet arrived from the remote machine within `suspect_timeout`
+    PeerSuspected(String),
+    /// a packet arrived from a suspected remote machine
+    PeerRecovered(String),
+}
+
+
+/// Channels the membership events are sent to
+#[derive(Clone, Default)]
+pub struct PeerEvents {
+    senders: Arc<Mutex<Vec<Sender<PeerEvent>>>>,
+}
+
+
+impl PeerEvents {
+    pub fn new() -> PeerEvents {
+        PeerEvents::default()
+    }
+
+    /// Get a channel that receives the membership events from now on
+    pub fn subscribe(&self) -> Receiver<PeerEvent> {
+        let (sender, receiver) = channel();
+        self.lock().push(sender);
+        receiver
+    }
+
+    /// Send a membership event to all channels. The channels dropped by the application
+    /// are removed.
+    pub fn emit(&self, event: PeerEvent) {
+        info!("Membership event: {:?}", event);
+        self.lock().retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Sender<PeerEvent>>> {
+        self.senders.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+
+// The last time a packet arrived from a remote machine
+struct LastSeen {
+    instant: Instant,
+    time: SystemTime,
+    // if `PeerEvent::PeerSuspected` was sent since
+    suspected: bool,
+}
+
+
+/// Failure detector that tracks the last time a packet arrived from each remote machine.
+/// The heartbeats keep the idle machines alive.
+#[derive(Clone)]
+pub struct FailureDetector {
+    // by the node IDs of the remote machines
+    last_seen: Arc<RwLock<HashMap<String, LastSeen>>>,
+    suspect_timeout: Duration,
+    dead_timeout: Duration,
+    events: PeerEvents,
+}
+
+
+impl FailureDetector {
+    /// Create a failure detector that suspects the remote machines that sent nothing within
+    /// `suspect_timeout`, and considers them dead after `dead_timeout`.
+    /// The suspicions and the recoveries are sent to `events`.
+    pub fn new(
+        suspect_timeout: Duration, dead_timeout: Duration, events: PeerEvents,
+    ) -> FailureDetector {
+        FailureDetector {
+            last_seen: Arc::new(RwLock::new(HashMap::new())),
+            suspect_timeout,
+            dead_timeout,
+            events,
+        }
+    }
+
+    /// Record that a packet arrived from the remote machine `name`
+    pub fn heard_from(&self, name: String) {
+        let last_seen = LastSeen {
+            instant: Instant::now(),
+            time: SystemTime::now(),
+            suspected: false,
+        };
+        let was_suspected = write_lock(&self.last_seen).insert(name.clone(), last_seen)
+            .map(|last_seen| last_seen.suspected)
+            .unwrap_or(false);
+        if was_suspected {
+            self.events.emit(PeerEvent::PeerRecovered(name));
+        }
+    }
+
+    /// Suspect the remote machines that have sent nothing for `suspect_timeout`.
+    /// It is called periodically.
+    pub fn check(&self) {
+        let suspects: Vec<String> = write_lock(&self.last_seen).iter_mut()
+            .filter(|(_, last_seen)| {
+                !last_seen.suspected && last_seen.instant.elapsed() >= self.suspect_timeout
+            })
+            .map(|(name, last_seen)| {
+                last_seen.suspected = true;
+                name.clone()
+            })
+            .collect();
+        suspects.into_iter().for_each(|name| self.events.emit(PeerEvent::PeerSuspected(name)));
+    }
+
+    /// Get the liveness of every remote machine a packet ever arrived from
+    pub fn get_status(&self) -> HashMap<String, PeerStatus> {
+        read_lock(&self.last_seen).iter()
+            .map(|(name, last_seen)| {
+                let silence = last_seen.instant.elapsed();
+                let liveness =
+                    if silence >= self.dead_timeout {
+                        Liveness::Dead
+                    } else if silence >= self.suspect_timeout {
+                        Liveness::Suspect
+                    } else {
+                        Liveness::Alive
+                    };
+                (name.clone(), PeerStatus { liveness, last_seen: last_seen.time })
+            })
+            .collect()
+    }
+}
@@ -1,44 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::TryRecvError;
+use std::time::SystemTime;
 
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 
+use codec::Codec;
+use error::TmsnError;
+use liveness::Liveness;
+use liveness::PeerEvent;
+use liveness::PeerStatus;
 use packet::Packet;
 use perfstats::PerfStats;
+use BytesCallback;
+use Outgoing;
+use PacketCallback;
 
 
 /// A mock network module for the debugging purpose.
 /// It bypasses the network and allows interacting with the application (that uses tmsn) through
 /// the `mock_get` and `mock_send` methods.
-pub struct MockNetwork {
-    outbound_put: Sender<(Option<String>, Packet)>,
-    outbound_get: Receiver<(Option<String>, Packet)>,
-    callback: Box<dyn FnMut(String, Packet) + Sync + Send>,
+pub struct MockNetwork<C: Codec> {
+    codec: Arc<C>,
+    outbound_put: Sender<Outgoing>,
+    outbound_get: Receiver<Outgoing>,
+    callback: PacketCallback,
     pub _perf_stats: PerfStats,
 }
 
 
-impl MockNetwork {
+impl<C: Codec> MockNetwork<C> {
     pub fn new<T: 'static + DeserializeOwned>(
-        _port: u16,
-        _remote_ips: &Vec<String>,
+        port: u16,
+        remote_ips: &[String],
         mut callback: Box<dyn FnMut(String, T) + Sync + Send>,
-    ) -> MockNetwork {
+        codec: C,
+    ) -> MockNetwork<C> {
+        let codec = Arc::new(codec);
+        let decoder = codec.clone();
+        MockNetwork::start(port, remote_ips, Box::new(move |sender_name, content: &[u8]| {
+            match decoder.decode::<T>(content) {
+                Ok(content) => callback(sender_name, content),
+                Err(err) => error!("Cannot decode the packet from {}. Error: {}",
+                                   sender_name, err),
+            }
+        }), codec)
+    }
+
+    /// Create a mock network that passes the raw payload bytes to the callback
+    pub fn new_bytes(
+        port: u16,
+        remote_ips: &[String],
+        callback: BytesCallback,
+        codec: C,
+    ) -> MockNetwork<C> {
+        MockNetwork::start(port, remote_ips, callback, Arc::new(codec))
+    }
+
+    fn start(
+        _port: u16,
+        _remote_ips: &[String],
+        mut callback: BytesCallback,
+        codec: Arc<C>,
+    ) -> MockNetwork<C> {
         let (outbound_put, outbound_get) = channel();
-        let callback: Box<dyn FnMut(String, Packet) + Sync + Send> =
+        let callback: PacketCallback =
             Box::new(move |sender_name, packet| {
                 if packet.is_workload() {
-                    let content: T = serde_json::from_str(&packet.content.unwrap()).unwrap();
-                    callback(sender_name, content);
+                    callback(sender_name, &packet.content);
                 }
             });
         MockNetwork {
-            outbound_put: outbound_put,
-            outbound_get: outbound_get,
-            callback: callback,
+            codec,
+            outbound_put,
+            outbound_get,
+            callback,
             _perf_stats: PerfStats::new(),
         }
     }
@@ -47,25 +87,45 @@ impl MockNetwork {
         vec!["mock".to_string()]
     }
 
+    /// The mocked peer is always alive
+    pub fn peer_status(&self) -> HashMap<String, PeerStatus> {
+        let status = PeerStatus { liveness: Liveness::Alive, last_seen: SystemTime::now() };
+        vec![("mock".to_string(), status)].into_iter().collect()
+    }
+
+    /// The membership of the mocked network never changes
+    pub fn peer_events(&self) -> Receiver<PeerEvent> {
+        channel().1
+    }
+
     /// Send out a packet
-    pub fn send<T: Serialize>(&self, dest: Option<String>, packet_load: T) -> Result<(), ()> {
-        let safe_json = serde_json::to_string(&packet_load).unwrap();
-        let ret = self.outbound_put.send((dest, Packet::new(safe_json)));
-        if ret.is_ok() {
-            Ok(())
-        } else {
-            Err(())
-        }
+    pub fn send<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T,
+    ) -> Result<(), TmsnError> {
+        let payload = self.codec.encode(&packet_load)?;
+        self.send_bytes(dest, payload)
+    }
+
+    /// Send out a packet whose payload is already encoded, bypassing the codec
+    pub fn send_bytes(&self, dest: Option<String>, payload: Vec<u8>) -> Result<(), TmsnError> {
+        self.outbound_put.send((dest, Packet::new(payload))).map_err(|_| TmsnError::Closed)
     }
 
     /// Get the packet sent out by the application
-    pub fn mock_get(&mut self) -> Result<(Option<String>, Packet), TryRecvError> {
+    pub fn mock_get(&mut self) -> Result<Outgoing, TryRecvError> {
         self.outbound_get.try_recv()
     }
 
+    /// Send a packet with the raw payload bytes to the application
+    pub fn mock_send_bytes(&mut self, source: &str, payload: Vec<u8>) {
+        (self.callback)(source.to_string(), Packet::new(payload));
+    }
+
     /// Send a packet to the application
-    pub fn mock_send<T: Serialize>(&mut self, source: &String, packet_load: T) {
-        let safe_json = serde_json::to_string(&packet_load).unwrap();
-        (self.callback)(source.clone(), Packet::new(safe_json));
+    pub fn mock_send<T: Serialize>(&mut self, source: &str, packet_load: T) {
+        match self.codec.encode(&packet_load) {
+            Ok(payload) => (self.callback)(source.to_string(), Packet::new(payload)),
+            Err(err) => error!("Cannot encode the packet. Error: {}", err),
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,341 @@
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+use packet::JsonFormat;
+use packet::Packet;
+use packet::PacketType;
+use packet::WireFormat;
+
+
+// First byte of every binary frame. Legacy JSON frames always start with `[`, so the
+// receiver can tell the two formats apart by peeking at the first byte.
+const MAGIC: u8 = 0xA5;
+// magic (1) + payload length (4) + packet type (1) + packet index (4) + sent time (8)
+const HEADER_LEN: usize = 18;
+// First byte of the binary frames followed by a sequence number, which are sent only to
+// the machines supporting the `seq` feature
+const SEQ_MAGIC: u8 = 0xA6;
+// sequence number (4)
+const SEQ_LEN: usize = 4;
+// Frames announcing a larger payload are considered corrupted
+pub const MAX_PAYLOAD_LEN: usize = 1 << 30;
+// First byte of the hello message
+const HELLO_MAGIC: u8 = 0x5A;
+// Hello messages announcing a larger body are considered corrupted
+const MAX_HELLO_LEN: usize = 1 << 16;
+
+
+// Latest version of the protocol spoken by this version of tmsn
+pub const PROTOCOL_VERSION: u32 = 1;
+// Oldest version of the protocol spoken by this version of tmsn. Version 0 is spoken by
+// the older versions of tmsn, which do not send the hello message.
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+// Optional features supported by this version of tmsn, e.g. `compression`.
+// A feature is used on a connection only if both sides support it.
+pub const FEATURES: &[&str] = &[SEQ_FEATURE];
+// The binary frames carry the sequence number of the packet among the packets sent to the
+// remote machine, so that it can detect the lost, duplicated and reordered packets
+pub const SEQ_FEATURE: &str = "seq";
+
+
+// Handshake message. A receiver sends it right after it connects to a sender, and the sender
+// replies with its own, so that both sides learn about the machine behind the connection,
+// and agree on the protocol spoken on the connection.
+// The older versions of tmsn send nothing, and do not expect a reply.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Hello {
+    // the ID of the machine, `None` for the machine to be named by its address
+    #[serde(default)]
+    pub node_id: Option<String>,
+    // the port the machine is listening on, `None` if it does not listen.
+    // The IP of the listening address is the IP of the connection.
+    #[serde(default)]
+    pub port: Option<u16>,
+    // the latest protocol version spoken by the machine, 0 for the versions without one
+    #[serde(default)]
+    pub version: u32,
+    // the oldest protocol version spoken by the machine
+    #[serde(default)]
+    pub min_version: u32,
+    // the optional features supported by the machine
+    #[serde(default)]
+    pub features: Vec<String>,
+    // a random number identifying the running instance of the machine, which changes when
+    // the machine restarts, 0 for the versions without one
+    #[serde(default)]
+    pub session: u64,
+    // sent by a receiver that reconnects, so that the sender replays the packets it missed
+    #[serde(default)]
+    pub resume: Option<Resume>,
+    // sent by a sender that no longer keeps all the packets the receiver asked for, which is
+    // the sequence number of the first packet it replays
+    #[serde(default)]
+    pub replay_gap: Option<u32>,
+    // sent by a sender that cannot resume the sequence the receiver asked for, because it no
+    // longer numbers the packets to the receiver in that sequence, e.g. the receiver is named
+    // by the address of the connection, which changes when it reconnects. The packets are
+    // numbered from 0 again, so the receiver starts its sequence over.
+    #[serde(default)]
+    pub restarted: bool,
+}
+
+
+// Position of a receiver in the sequence of the packets from a sender
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resume {
+    // the session of the sender the packets were received from
+    pub session: u64,
+    // the sequence number of the next packet expected
+    pub seq: u32,
+}
+
+
+// Protocol agreed on by both sides of a connection
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+
+impl Hello {
+    pub fn new(node_id: Option<String>, port: Option<u16>) -> Hello {
+        Hello {
+            node_id,
+            port,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            session: rand::random(),
+            resume: None,
+            replay_gap: None,
+            restarted: false,
+        }
+    }
+
+    // Agree on the latest protocol version spoken by both machines, and on the features
+    // supported by both machines. Returns the reason if the machines speak no common version.
+    pub fn negotiate(&self, remote: &Hello) -> Result<Protocol, String> {
+        let version = self.version.min(remote.version);
+        if version < self.min_version.max(remote.min_version) {
+            return Err(format!(
+                "it speaks the protocol versions {} to {}, while this machine speaks {} to {}",
+                remote.min_version, remote.version, self.min_version, self.version));
+        }
+        let features = self.features.iter()
+            .filter(|feature| remote.features.contains(feature))
+            .cloned()
+            .collect();
+        Ok(Protocol { version, features })
+    }
+}
+
+
+// Check if the next message in the stream is a hello message.
+// Returns an error if the remote side closed the stream.
+pub fn is_hello<R: BufRead>(stream: &mut R) -> io::Result<bool> {
+    let buf = stream.fill_buf()?;
+    if buf.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+            "The stream was closed before any message."));
+    }
+    Ok(buf[0] == HELLO_MAGIC)
+}
+
+
+// Write the hello message to the stream.
+//
+// Layout: | magic: u8 | body length: u32 (big-endian) | JSON body ... |
+//
+// The body is JSON so that the fields added later can be skipped by the older versions.
+pub fn write_hello<W: Write>(stream: &mut W, hello: &Hello) -> io::Result<()> {
+    let body = serde_json::to_vec(hello)?;
+    stream.write_all(&[HELLO_MAGIC])?;
+    stream.write_all(&(body.len() as u32).to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+
+// Read the hello message from the stream
+pub fn read_hello<R: Read>(stream: &mut R) -> io::Result<Hello> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    if header[0] != HELLO_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Unknown hello message starting with {}.", header[0])));
+    }
+    let body_len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if body_len > MAX_HELLO_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Hello message announces a body of {} bytes.", body_len)));
+    }
+    let mut body = vec![0u8; body_len];
+    stream.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+
+// Write a single packet to the stream.
+//
+// Binary frame layout (all integers are big-endian):
+//
+//     | magic: u8 | payload length: u32 | packet type: u8 | index: u32 | sent time (us): u64 |
+//     | payload bytes ... |
+//
+// The legacy format is the JSON encoding of `JsonFormat` followed by a newline.
+pub fn write_frame<W: Write>(
+    stream: &mut W, format: &WireFormat, idx: u32, packet: &Packet,
+) -> io::Result<()> {
+    match format {
+        WireFormat::Binary => {
+            let payload = &packet.content;
+            if payload.len() > MAX_PAYLOAD_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Payload of {} bytes exceeds the frame limit.", payload.len())));
+            }
+            let sent_time = packet.sent_time.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0);
+            let mut header = [0u8; HEADER_LEN];
+            header[0] = MAGIC;
+            header[1..5].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+            header[5] = type_to_byte(&packet.packet_type);
+            header[6..10].copy_from_slice(&idx.to_be_bytes());
+            header[10..18].copy_from_slice(&sent_time.to_be_bytes());
+            stream.write_all(&header)?;
+            stream.write_all(payload)
+        },
+        WireFormat::Json => {
+            let json = serde_json::to_string(&(idx, packet))?;
+            stream.write_fmt(format_args!("{}\n", json))
+        },
+    }
+}
+
+
+// Encode a single packet into a frame, which can be written to any number of streams
+pub fn encode_frame(format: &WireFormat, idx: u32, packet: &Packet) -> io::Result<Arc<[u8]>> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + packet.content.len());
+    write_frame(&mut frame, format, idx, packet)?;
+    Ok(frame.into())
+}
+
+
+// Write a frame encoded by `encode_frame` to the stream, adding the sequence number `seq`
+// if it is a binary frame.
+//
+// Layout: | magic: u8 (SEQ_MAGIC) | ...the rest of the binary frame header... | seq: u32 |
+//         | payload bytes ... |
+pub fn write_encoded_frame<W: Write>(
+    stream: &mut W, frame: &[u8], seq: Option<u32>,
+) -> io::Result<()> {
+    match seq {
+        Some(seq) if frame.len() >= HEADER_LEN && frame[0] == MAGIC => {
+            stream.write_all(&[SEQ_MAGIC])?;
+            stream.write_all(&frame[1..HEADER_LEN])?;
+            stream.write_all(&seq.to_be_bytes())?;
+            stream.write_all(&frame[HEADER_LEN..])
+        },
+        _ => stream.write_all(frame),
+    }
+}
+
+
+// Read the next packet from the stream, accepting both binary and legacy JSON frames.
+// Returns `Ok(None)` if the remote side closed the stream.
+pub fn read_frame<R: BufRead>(stream: &mut R) -> io::Result<Option<JsonFormat>> {
+    let first_byte = {
+        let buf = stream.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        buf[0]
+    };
+    if first_byte == MAGIC || first_byte == SEQ_MAGIC {
+        read_binary_frame(stream).map(Some)
+    } else {
+        read_json_frame(stream)
+    }
+}
+
+
+fn read_binary_frame<R: BufRead>(stream: &mut R) -> io::Result<JsonFormat> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let payload_len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Frame announces a payload of {} bytes.", payload_len)));
+    }
+    let packet_type = byte_to_type(header[5])?;
+    let idx = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    let mut sent_time = [0u8; 8];
+    sent_time.copy_from_slice(&header[10..18]);
+    let sent_time = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(sent_time));
+    let seq = if header[0] == SEQ_MAGIC {
+        let mut seq = [0u8; SEQ_LEN];
+        stream.read_exact(&mut seq)?;
+        Some(u32::from_be_bytes(seq))
+    } else {
+        None
+    };
+
+    let mut content = vec![0u8; payload_len];
+    stream.read_exact(&mut content)?;
+    let packet = Packet {
+        content,
+        sent_time,
+        receive_time: None,
+        packet_type,
+        id: idx,
+        seq,
+    };
+    Ok((idx, packet))
+}
+
+
+fn read_json_frame<R: BufRead>(stream: &mut R) -> io::Result<Option<JsonFormat>> {
+    loop {
+        let mut json = String::new();
+        if stream.read_line(&mut json)? == 0 {
+            return Ok(None);
+        }
+        if json.trim().is_empty() {
+            trace!("Skipped an empty JSON frame");
+            continue;
+        }
+        return serde_json::from_str(&json)
+            .map(|(idx, packet): JsonFormat| Some((idx, Packet { id: idx, ..packet })))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData,
+                format!("Cannot parse the JSON frame `{}`. Error: {}", json.trim(), err)));
+    }
+}
+
+
+fn type_to_byte(packet_type: &PacketType) -> u8 {
+    match packet_type {
+        PacketType::Message => 0,
+        PacketType::Echo => 1,
+        PacketType::Heartbeat => 2,
+        PacketType::HeartbeatEcho => 3,
+    }
+}
+
+
+fn byte_to_type(byte: u8) -> io::Result<PacketType> {
+    match byte {
+        0 => Ok(PacketType::Message),
+        1 => Ok(PacketType::Echo),
+        2 => Ok(PacketType::Heartbeat),
+        3 => Ok(PacketType::HeartbeatEcho),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Unknown packet type {}.", byte))),
+    }
+}
+
@@ -1,13 +1,261 @@
+mod frame;
 mod sender;
 mod receiver;
+mod replay;
+mod sequence;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
 
-use packet::Packet;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
+
+use error::TmsnError;
+use queue::OutboundQueue;
+use queue::SlowPeerPolicy;
+use self::frame::Hello;
+use packet::WireFormat;
+use retry::RetryPolicy;
+use EventCallback;
 use LockedStream;
+use Outgoing;
+use PacketCallback;
+use ShutdownReport;
+
+pub use self::sender::Subscriber;
+pub use self::sequence::Sequence;
+pub use self::sequence::SequenceStats;
+
+
+/// Flag shared by all threads of a network, set to `true` to stop them
+pub type StopSignal = Arc<AtomicBool>;
+
+/// Streams to the remote machines this machine is subscribed to, by their addresses
+pub type Subscriptions = Arc<RwLock<HashMap<RemoteAddr, TcpStream>>>;
+
+/// Node IDs of the remote machines, by their listening addresses
+pub type AddressBook = Arc<RwLock<HashMap<RemoteAddr, String>>>;
+
+/// Counts of the lost, duplicated and reordered packets, by the node IDs of their senders
+pub type SequenceBook = Arc<RwLock<HashMap<String, SequenceStats>>>;
+
+/// Interval at which the blocking loops check if the network has been stopped
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time to wait for the hello message from a new connection
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// How the packets waiting to be written to each subscriber are queued
+#[derive(Clone, Copy, Debug)]
+pub struct PeerQueueOptions {
+    /// the maximum number of packets waiting to be written to a subscriber
+    pub capacity: usize,
+    /// what happens when a subscriber falls `capacity` packets behind
+    pub slow_peer_policy: SlowPeerPolicy,
+    /// the maximum number of packets kept for each subscriber, which are replayed if it
+    /// reconnects after missing them. It should not exceed `capacity`.
+    pub replay_capacity: usize,
+    /// the maximum number of bytes of the packets kept for each subscriber
+    pub replay_max_bytes: usize,
+    /// the time the packets kept for a subscriber that has disconnected are kept
+    pub replay_retention: Duration,
+}
+
+
+/// How the packets from each remote machine are put back in the order they were sent out
+#[derive(Clone, Copy, Debug)]
+pub struct ReorderOptions {
+    /// the maximum number of packets held while waiting for a missing packet
+    pub window: usize,
+    /// the time a missing packet is waited for
+    pub timeout: Duration,
+}
+
+
+/// Options of the TCP connections to the remote machines
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    /// the time after which a connection that receives nothing is considered lost,
+    /// `None` to wait forever
+    pub read_timeout: Option<Duration>,
+    /// the time after which a connection that accepts no data is considered lost,
+    /// `None` to wait forever
+    pub write_timeout: Option<Duration>,
+    /// the idle time before the TCP keepalive probes are sent, `None` to send none
+    pub keepalive: Option<Duration>,
+    /// send out the small packets without delay, i.e. set `TCP_NODELAY`
+    pub nodelay: bool,
+}
+
+
+impl SocketOptions {
+    /// Apply the options to a connected stream
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+            None => socket.set_keepalive(false),
+        }
+    }
+}
+
+
+/// Listening address of a remote machine
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RemoteAddr {
+    /// an IPv4 or IPv6 address
+    Ip(SocketAddr),
+    /// a host name and a port, the host name is resolved every time it is connected to
+    Host(String, u16),
+}
+
+
+impl RemoteAddr {
+    /// Get the port of the remote machine
+    pub fn port(&self) -> u16 {
+        match self {
+            RemoteAddr::Ip(addr) => addr.port(),
+            RemoteAddr::Host(_, port) => *port,
+        }
+    }
+
+    /// Connect to the remote machine, trying each address it resolves to for at most `timeout`,
+    /// and giving up once the network is stopped
+    pub fn connect(&self, timeout: Duration, stop_signal: &StopSignal) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = match self {
+            RemoteAddr::Ip(addr) => vec![*addr],
+            RemoteAddr::Host(host, port) => (host.as_str(), *port).to_socket_addrs()?.collect(),
+        };
+        let mut last_err = io::Error::new(
+            ErrorKind::AddrNotAvailable, format!("{} resolves to no address", self));
+        for addr in addrs {
+            if is_stopped(stop_signal) {
+                return Err(io::Error::new(ErrorKind::Interrupted, "the network is stopped"));
+            }
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteAddr::Ip(addr) => write!(f, "{}", addr),
+            RemoteAddr::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+
+/// Check if the network has been stopped
+pub fn is_stopped(stop_signal: &StopSignal) -> bool {
+    stop_signal.load(Ordering::SeqCst)
+}
+
+
+/// Sleep for `duration`, or until the network is stopped
+pub fn sleep_or_stop(duration: Duration, stop_signal: &StopSignal) {
+    let deadline = Instant::now() + duration;
+    let mut now = Instant::now();
+    while now < deadline && !is_stopped(stop_signal) {
+        sleep(POLL_INTERVAL.min(deadline - now));
+        now = Instant::now();
+    }
+}
+
+
+/// Lock for reading, and recover the data if a thread panicked while holding the lock
+pub fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|err| err.into_inner())
+}
+
+
+/// Lock for writing, and recover the data if a thread panicked while holding the lock
+pub fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|err| err.into_inner())
+}
+
+
+/// Threads of a running network
+pub struct NetworkThreads {
+    sender: Option<JoinHandle<Vec<Outgoing>>>,
+    receiver: Option<JoinHandle<()>>,
+    subscriptions: Subscriptions,
+    address_book: AddressBook,
+    sequences: SequenceBook,
+    local_addr: Option<SocketAddr>,
+}
+
+
+impl NetworkThreads {
+    /// Get the node IDs of the remote machines this machine is currently subscribed to
+    pub fn get_subscriptions(&self) -> Vec<String> {
+        let address_book = read_lock(&self.address_book);
+        read_lock(&self.subscriptions).keys()
+            .map(|addr| address_book.get(addr).cloned().unwrap_or_else(|| addr.to_string()))
+            .collect()
+    }
+
+    /// Get the address this machine is listening on, `None` if it does not listen
+    pub fn get_local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Get the node IDs of the remote machines that have connected to this machine
+    pub fn get_address_book(&self) -> AddressBook {
+        self.address_book.clone()
+    }
+
+    /// Get the counts of the lost, duplicated and reordered packets from each remote machine
+    pub fn get_sequence_stats(&self) -> HashMap<String, SequenceStats> {
+        read_lock(&self.sequences).clone()
+    }
+
+    /// Wait for the threads to exit after the network is stopped
+    pub fn join(self) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        if let Some(sender) = self.sender {
+            match sender.join() {
+                Ok(undelivered) => report.undelivered = undelivered,
+                Err(_) => report.errors.push("The sender thread panicked.".to_string()),
+            }
+        }
+        if let Some(receiver) = self.receiver {
+            if receiver.join().is_err() {
+                report.errors.push("The receiver thread panicked.".to_string());
+            }
+        }
+        report
+    }
+}
 
 
 ///
@@ -24,41 +272,62 @@ use LockedStream;
 /// There are two modes for how connections are established:
 ///
 /// * `is_two_way=false`: The list of IPs for subscription are
-/// limited to those provided in `init_remote_ips` at the initialization.
-/// The machine listens only to those IPs.
-/// In this mode, it is possible that a one-way connection exists between some
-/// machines, namely, a machine A listens to a machine B, but the machine B
-/// does not listen to the machine A.
+///   limited to those provided in `init_remote_ips` at the initialization.
+///   The machine listens only to those IPs.
+///   In this mode, it is possible that a one-way connection exists between some
+///   machines, namely, a machine A listens to a machine B, but the machine B
+///   does not listen to the machine A.
 /// * `is_two_way=true`: The machine first subscribes and listens to all IPs provided
-/// in `init_remote_ips` at the initialization.
-/// In addition, the machine also subscribes to all other machines that are subscribing to
-/// this machine, even if the IP of the other machine is not listed in `init_remote_ips`.
-/// In this mode, two machine are either not listening to each other,
-/// or connected in both directions (both listening to the other).
+///   in `init_remote_ips` at the initialization.
+///   In addition, the machine also subscribes to all other machines that are subscribing to
+///   this machine, even if the IP of the other machine is not listed in `init_remote_ips`.
+///   In this mode, two machine are either not listening to each other,
+///   or connected in both directions (both listening to the other).
 ///
 /// ## Parameters
-/// * `init_remote_ips` - a list of IPs to which this computer makes a connection initially.
-/// * `port` - the port number that the machines in the network are listening to.
-/// `port` has to be the same value for all machines.
+/// * `init_remote_ips` - a list of addresses to which this computer makes a connection
+///   initially, as `host:port`, or as `host` for the machines listening on `port`.
+///   `host` is an IPv4 address, an IPv6 address in brackets, or a host name that is
+///   resolved every time it is connected to.
+/// * `bind_addr` - the local address to listen on, e.g. `0.0.0.0` for all interfaces.
+/// * `port` - the port number that this machine is listening to, or 0 for a port assigned
+///   by the OS (see `NetworkThreads::get_local_addr`).
+///   It is sent to the remote machines when connecting, so that they can subscribe back.
+/// * `node_id` - the ID by which the remote machines identify this machine.
+///   If it is `None`, the remote machines use the address of this machine instead.
 /// * `is_two_way` - a flag that indicates which IPs this machine will listen to.
-/// See description above.
-/// * `data_remote` - a sender of the channel for transmitting the data received from the network.
-/// See the notes below.
-/// * `data_local` - a reciever of the channel for transmitting the data to
-/// be broadcasted to the network. See the notes below.
+///   See description above.
+/// * `outbound` - the queue of the packets to be broadcasted to the network, which also
+///   receives the receipts of the incoming packets from the machines subscribing to
+///   this machine. See the notes below.
+/// * `callback` - a callback function to be called with the packets received from the network.
+/// * `wire_format` - the encoding of the outgoing packets. It can be changed while the network
+///   is running. Incoming packets are accepted in either format.
+/// * `peer_queue` - how the packets are queued for each subscriber, which has its own queue
+///   so that a slow subscriber does not hold up the others.
+/// * `socket_options` - the timeouts and the options of the connections. A connection that
+///   times out is closed, and handled in the same way as a broken connection.
+/// * `reorder` - if set, the packets from each remote machine are delivered in
+///   the order they were sent out, holding a bounded number of packets for a bounded time
+///   while waiting for a missing packet. Otherwise they are delivered as they arrive.
+/// * `on_event` - a callback function to be called when a connection to a remote machine
+///   is set up, or is found broken and removed, on both the sender and the receiver sides.
+/// * `retry_policy` - how the connections to the remote machines are retried,
+///   both initially and after a connection is lost.
+/// * `stop_signal` - set to `true` to stop the network. The streams are closed and
+///   the listening port is released. Use the returned `NetworkThreads` to wait for the
+///   network threads to exit.
 ///
 /// ## Notes
 /// In order to send/receive data using the network, your program should first create
-/// two [mpsc channels](https://doc.rust-lang.org/std/sync/mpsc/),
-/// one for incoming packets, one for outgoing packets.
+/// an `OutboundQueue` for the outgoing packets, and a callback function for
+/// the incoming packets.
 /// Then start the network using the `start_network` function,
-/// and pass the sender or receiver of the two channels to the
-/// network module as the function parameters.
-/// The network module will broadcast out all packets received from the channel,
-/// and also send the packets received from the network to the other channel.
-/// Correspondingly, your program should write the data to be sent out to the channel,
-/// and read the other channel to receive the packets from other machines.
-/// See the example below for demonstration.
+/// and pass both to the network module as the function parameters.
+/// The network module will broadcast out all packets taken from the queue,
+/// and also call the callback function with the packets received from the network.
+/// Correspondingly, your program should push the data to be sent out to the queue.
+/// The capacity of the queue bounds the memory used by the packets waiting to be sent out.
 ///
 /// The network structure between the machines are decided by your program, specifically by
 /// explicitly setting the list of IPs to be subscribed from each machine.
@@ -83,64 +352,198 @@ use LockedStream;
 /// The full workflow of the network module is described in the following plot.
 ///
 /// ![](https://www.lucidchart.com/publicSegments/view/9c3b7a65-55ad-4df5-a5cb-f3154b692ecd/image.png)
+#[allow(clippy::too_many_arguments)]
 pub fn start_network(
-        init_remote_ips: &Vec<String>, port: u16, is_two_way: bool,
-        outbound_send: Sender<(Option<String>, Packet)>,
-        outbound_recv: Receiver<(Option<String>, Packet)>,
-        callback: Box<dyn FnMut(String, Packet) + Sync + Send>,
-) -> Result<LockedStream, &'static str> {
+        init_remote_ips: &[String], bind_addr: IpAddr, port: u16, node_id: Option<String>,
+        is_two_way: bool,
+        outbound: OutboundQueue,
+        callback: PacketCallback,
+        wire_format: Arc<RwLock<WireFormat>>,
+        peer_queue: PeerQueueOptions,
+        socket_options: SocketOptions,
+        reorder: Option<ReorderOptions>,
+        on_event: EventCallback,
+        retry_policy: RetryPolicy,
+        stop_signal: StopSignal,
+) -> Result<(LockedStream, NetworkThreads), TmsnError> {
     // receiver initiates the connection
 
     info!("Starting the network module.");
-    let (ip_send, ip_recv): (Sender<SocketAddr>, Receiver<SocketAddr>) = mpsc::channel();
+    let init_remote_addrs = parse_remote_ips(init_remote_ips, port)?;
+    let (ip_send, ip_recv): (Sender<RemoteAddr>, Receiver<RemoteAddr>) = mpsc::channel();
     // sender accepts remote connections
-    let sender_state = {
-        if is_two_way {
-            sender::start_sender(port, outbound_recv, Some(ip_send.clone()))
-        } else {
-            sender::start_sender(port, outbound_recv, None)
-        }
-    };
-    if sender_state.is_ok() {
-        // receiver initiates remote connections
-        receiver::start_receiver(port, outbound_send, callback, ip_recv);
-        send_initial_ips(init_remote_ips, ip_send, port);
-    }
-    sender_state
+    let remote_ip_send = if is_two_way { Some(ip_send.clone()) } else { None };
+    let address_book: AddressBook = Arc::new(RwLock::new(HashMap::new()));
+    let sequences: SequenceBook = Arc::new(RwLock::new(HashMap::new()));
+    let (streams, sender, local_addr) = sender::start_sender(
+        bind_addr, port, node_id.clone(), outbound.clone(), remote_ip_send,
+        address_book.clone(), wire_format, peer_queue, socket_options, on_event.clone(),
+        stop_signal.clone())?;
+    // receiver initiates remote connections
+    let receipts = receiver::Receipts::new(outbound, streams.clone());
+    let (subscriptions, receiver) = receiver::start_receiver(
+        Hello::new(node_id, Some(local_addr.port())), Some(receipts), callback, ip_recv,
+        address_book.clone(), retry_policy, socket_options, sequences.clone(), reorder,
+        on_event, stop_signal);
+    send_initial_addrs(init_remote_addrs, ip_send);
+    Ok((streams, NetworkThreads {
+        sender: Some(sender),
+        receiver: Some(receiver),
+        subscriptions,
+        address_book,
+        sequences,
+        local_addr: Some(local_addr),
+    }))
 }
 
 
-#[allow(dead_code)]
-fn start_network_only_send(
-        port: u16, data_local: Receiver<(Option<String>, Packet)>,
-) -> Result<LockedStream, &'static str> {
+/// Starts a network that only sends out the packets to the machines subscribing to it
+///
+/// The parameters are the same as `start_network`.
+#[allow(clippy::too_many_arguments)]
+pub fn start_network_only_send(
+        bind_addr: IpAddr, port: u16, node_id: Option<String>, outbound: OutboundQueue,
+        wire_format: Arc<RwLock<WireFormat>>,
+        peer_queue: PeerQueueOptions,
+        socket_options: SocketOptions,
+        on_event: EventCallback,
+        stop_signal: StopSignal,
+) -> Result<(LockedStream, NetworkThreads), TmsnError> {
     info!("Starting the network (send only) module.");
-    sender::start_sender(port, data_local, None)
+    let address_book: AddressBook = Arc::new(RwLock::new(HashMap::new()));
+    let (streams, sender, local_addr) = sender::start_sender(
+        bind_addr, port, node_id, outbound, None,
+        address_book.clone(), wire_format, peer_queue, socket_options, on_event,
+        stop_signal)?;
+    let subscriptions = Arc::new(RwLock::new(HashMap::new()));
+    Ok((streams, NetworkThreads {
+        sender: Some(sender),
+        receiver: None,
+        subscriptions,
+        address_book,
+        sequences: Arc::new(RwLock::new(HashMap::new())),
+        local_addr: Some(local_addr),
+    }))
 }
 
 
-#[allow(dead_code)]
-fn start_network_only_recv(
-    remote_ips: &Vec<String>, port: u16,
-    outbound_send: Sender<(Option<String>, Packet)>,
-    callback: Box<dyn FnMut(String, Packet) + Sync + Send>,
-) -> Result<(), &'static str> {
+/// Starts a network that only receives the packets from the machines in `remote_ips`
+///
+/// The parameters are the same as `start_network`. Nothing is sent out, so the receipts of
+/// the incoming packets are dropped.
+#[allow(clippy::too_many_arguments)]
+pub fn start_network_only_recv(
+    remote_ips: &[String], port: u16, node_id: Option<String>,
+    callback: PacketCallback,
+    retry_policy: RetryPolicy,
+    socket_options: SocketOptions,
+    reorder: Option<ReorderOptions>,
+    on_event: EventCallback,
+    stop_signal: StopSignal,
+) -> Result<NetworkThreads, TmsnError> {
     info!("Starting the network (receive only) module.");
-    let (ip_send, ip_recv): (Sender<SocketAddr>, Receiver<SocketAddr>) = mpsc::channel();
-    receiver::start_receiver(port, outbound_send, callback, ip_recv);
-    send_initial_ips(remote_ips, ip_send, port);
-    Ok(())
-}
-
-
-fn send_initial_ips(remote_ips: &Vec<String>, ip_send: Sender<SocketAddr>, port: u16) {
-    remote_ips.iter().for_each(|ip| {
-        let socket_addr: SocketAddr =
-            (ip.clone() + ":" + port.to_string().as_str()).parse().expect(
-                &format!("Failed to parse initial remote IP `{}:{}`.", ip, port)
-            );
-        ip_send.send(socket_addr).expect(
-            "Failed to send the initial remote IP to the receivers listener."
-        );
+    let remote_addrs = parse_remote_ips(remote_ips, port)?;
+    let (ip_send, ip_recv): (Sender<RemoteAddr>, Receiver<RemoteAddr>) = mpsc::channel();
+    let address_book: AddressBook = Arc::new(RwLock::new(HashMap::new()));
+    let sequences: SequenceBook = Arc::new(RwLock::new(HashMap::new()));
+    // nothing listens on this machine, so the remote machines do not subscribe back
+    let (subscriptions, receiver) = receiver::start_receiver(
+        Hello::new(node_id, None), None, callback, ip_recv, address_book.clone(),
+        retry_policy, socket_options, sequences.clone(), reorder, on_event, stop_signal);
+    send_initial_addrs(remote_addrs, ip_send);
+    Ok(NetworkThreads {
+        sender: None,
+        receiver: Some(receiver),
+        subscriptions,
+        address_book,
+        sequences,
+        local_addr: None,
+    })
+}
+
+
+/// Parse the address of a remote machine, given either as `host:port`,
+/// or as `host` of a machine listening on `default_port`
+///
+/// `host` is an IPv4 address, an IPv6 address (in brackets if followed by the port),
+/// or a host name.
+pub fn parse_addr(addr: &str, default_port: u16) -> Result<RemoteAddr, TmsnError> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(RemoteAddr::Ip(addr));
+    }
+    let ip = addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(addr);
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok(RemoteAddr::Ip(SocketAddr::new(ip, default_port)));
+    }
+    let invalid = || TmsnError::InvalidAddress(addr.to_string());
+    let (host, port) = match addr.rfind(':') {
+        Some(index) => (&addr[..index], addr[index + 1..].parse().map_err(|_| invalid())?),
+        None => (addr, default_port),
+    };
+    if !is_host_name(host) {
+        return Err(invalid());
+    }
+    Ok(RemoteAddr::Host(host.to_lowercase(), port))
+}
+
+
+// Check if `host` is a valid DNS name, e.g. `worker-3.internal`
+fn is_host_name(host: &str) -> bool {
+    host.len() <= 253 && host.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 &&
+            !label.starts_with('-') && !label.ends_with('-') &&
+            label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+
+/// Get the node ID of the machine `dest`, which is either a node ID, or the address of
+/// a machine as accepted by `parse_addr`.
+///
+/// The address of a machine that has not announced its node ID is used as its node ID.
+pub fn resolve_node_id(address_book: &AddressBook, dest: &str, default_port: u16) -> String {
+    let address_book = read_lock(address_book);
+    // a node ID might also look like a host name
+    if address_book.values().any(|node_id| node_id == dest) {
+        return dest.to_string();
+    }
+    match parse_addr(dest, default_port) {
+        Ok(addr) => address_book.get(&addr).cloned().unwrap_or_else(|| addr.to_string()),
+        Err(_) => dest.to_string(),
+    }
+}
+
+
+fn parse_remote_ips(remote_ips: &[String], port: u16) -> Result<Vec<RemoteAddr>, TmsnError> {
+    remote_ips.iter().map(|ip| parse_addr(ip, port)).collect()
+}
+
+
+fn send_initial_addrs(remote_addrs: Vec<RemoteAddr>, ip_send: Sender<RemoteAddr>) {
+    remote_addrs.into_iter().for_each(|addr| {
+        if let Err(err) = ip_send.send(addr) {
+            error!("Failed to send the initial remote IP {} to the receivers listener.", err.0);
+        }
     });
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse_addr;
+
+    #[test]
+    fn test_parse_addr() {
+        let parse = |addr: &str| parse_addr(addr, 8080).map(|addr| addr.to_string());
+        assert_eq!(parse("10.0.0.1").unwrap(), "10.0.0.1:8080");
+        assert_eq!(parse("10.0.0.1:9000").unwrap(), "10.0.0.1:9000");
+        assert_eq!(parse("::1").unwrap(), "[::1]:8080");
+        assert_eq!(parse("[::1]").unwrap(), "[::1]:8080");
+        assert_eq!(parse("[::1]:9000").unwrap(), "[::1]:9000");
+        assert_eq!(parse("Worker-3.internal").unwrap(), "worker-3.internal:8080");
+        assert_eq!(parse("worker-3.internal:9000").unwrap(), "worker-3.internal:9000");
+        for invalid in ["", "not an ip", "worker_3", "-worker", "worker..internal", "worker:port"] {
+            assert!(parse(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+}
@@ -1,107 +1,449 @@
 use bufstream::BufStream;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io;
 use std::io::BufRead;
+use std::io::ErrorKind;
+use std::net::Shutdown;
 use std::net::SocketAddr;
 use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::RecvTimeoutError;
 use std::time::Duration;
+use std::time::Instant;
 
+use std::thread::JoinHandle;
 use std::thread::sleep;
 use std::thread::spawn;
 
-use packet::JsonFormat;
+use error::TmsnError;
+use liveness::Connection;
+use liveness::PeerEvent;
 use packet::Packet;
+use queue::OutboundQueue;
+use retry::RetryPolicy;
+use super::AddressBook;
+use super::HELLO_TIMEOUT;
+use super::POLL_INTERVAL;
+use super::RemoteAddr;
+use super::ReorderOptions;
+use super::SocketOptions;
+use super::StopSignal;
+use super::Sequence;
+use super::SequenceBook;
+use super::Subscriptions;
+use super::frame::Hello;
+use super::frame::Protocol;
+use super::frame::Resume;
+use super::frame::is_hello;
+use super::frame::read_frame;
+use super::frame::read_hello;
+use super::frame::write_hello;
+use super::is_stopped;
+use super::read_lock;
+use super::sleep_or_stop;
+use super::write_lock;
 
+use EventCallback;
+use LockedStream;
+use PacketCallback;
 
-// Start all receiver routines
+
+// The queue the receipts of the incoming packets are put in, to be sent back by the sender
+#[derive(Clone)]
+pub struct Receipts {
+    outbound: OutboundQueue,
+    // the machines subscribing to this machine, the only ones a receipt can be sent to
+    subscribers: LockedStream,
+}
+
+
+impl Receipts {
+    pub fn new(outbound: OutboundQueue, subscribers: LockedStream) -> Receipts {
+        Receipts { outbound, subscribers }
+    }
+
+    // Queue up the receipt of a packet from `sender_name`. It is dropped if `sender_name` does
+    // not subscribe to this machine, since nothing would take it out of the queue.
+    fn send(&self, sender_name: &str, receipt: Packet) -> Result<(), TmsnError> {
+        if !read_lock(&self.subscribers).iter().any(|s| s.name == sender_name) {
+            return Ok(());
+        }
+        // the receipts neither wait for room in the queue nor take it
+        self.outbound.force_push((Some(sender_name.to_string()), receipt))
+    }
+}
+
+
+// Start all receiver routines, which send `hello` to every remote machine they connect to
+//
+// The packets from each remote machine are tracked by their sequence numbers, and counted in
+// `sequences` if lost, duplicated or reordered. If `reorder` is set, the packets are
+// delivered in the order they were sent out. The connections that are set up and lost are
+// reported via `on_event`. The receipts of the incoming packets are sent back via `receipts`,
+// or not at all if it is `None`.
+//
+// Returns the streams to the remote machines that are currently connected, and a thread that
+// exits after the network is stopped and all receivers have exited.
+#[allow(clippy::too_many_arguments)]
 pub fn start_receiver(
-        port: u16,
-        outbound_send: Sender<(Option<String>, Packet)>,
-        callback: Box<dyn FnMut(String, Packet) + Sync + Send>,
-        remote_ip_recv: Receiver<SocketAddr>) {
-    spawn(move|| {
+        hello: Hello,
+        receipts: Option<Receipts>,
+        callback: PacketCallback,
+        remote_ip_recv: Receiver<RemoteAddr>,
+        address_book: AddressBook,
+        retry_policy: RetryPolicy,
+        socket_options: SocketOptions,
+        sequences: SequenceBook,
+        reorder: Option<ReorderOptions>,
+        on_event: EventCallback,
+        stop_signal: StopSignal) -> (Subscriptions, JoinHandle<()>) {
+    // a copy of each connected stream, also used for closing the streams on shutdown
+    let active_streams: Subscriptions = Arc::new(RwLock::new(HashMap::new()));
+    let subscriptions = active_streams.clone();
+    let handle = spawn(move|| {
         // If a new neighbor occurs, launch receiver to receive data from it
         info!("now entering receivers listener");
-        let mut receivers = HashSet::new();
+        let receivers = Arc::new(RwLock::new(HashSet::new()));
+        // the IP addresses of the remote machines that are connected
+        let peer_addrs = Arc::new(RwLock::new(HashSet::new()));
         let f = Arc::new(RwLock::new(callback));
-        while let Ok(mut remote_addr) = remote_ip_recv.recv() {
-            remote_addr.set_port(port);
-            if !receivers.contains(&remote_addr) {
+        let mut threads = vec![];
+        while !is_stopped(&stop_signal) {
+            let remote_addr = match remote_ip_recv.recv_timeout(POLL_INTERVAL) {
+                Ok(remote_addr) => remote_addr,
+                Err(RecvTimeoutError::Timeout) => continue,
+                // no more new neighbors, keep running the existing receivers
+                Err(RecvTimeoutError::Disconnected) => {
+                    sleep(POLL_INTERVAL);
+                    continue;
+                },
+            };
+            // a machine subscribed to by its host name is not subscribed to again by its IP
+            if let RemoteAddr::Ip(addr) = remote_addr {
+                if read_lock(&peer_addrs).contains(&addr) {
+                    info!("(Skipped) Already subscribed to {}", addr);
+                    continue;
+                }
+            }
+            // a receiver stays in `receivers` while it is connected or reconnecting,
+            // and is removed once it gives up so that the remote address can be subscribed again
+            if write_lock(&receivers).insert(remote_addr.clone()) {
                 let callback = f.clone();
-                let addr = remote_addr.clone();
-                let outbound = outbound_send.clone();
-                receivers.insert(remote_addr.clone());
-                spawn(move || {
-                    let mut tcp_stream = None;
-                    let mut attempt = 0;
-                    while tcp_stream.is_none() && attempt < 3 {
-                        attempt += 1;
-                        tcp_stream = match TcpStream::connect(remote_addr) {
-                            Ok(_tcp_stream) => Some(_tcp_stream),
-                            Err(error) => {
-                                info!("(retry in 10 secs) Error: {}.
-                                    Failed to connect to remote address {}", error, remote_addr);
-                                sleep(Duration::from_secs(10));
-                                None
-                            }
+                let receipts = receipts.clone();
+                let receivers = receivers.clone();
+                let active_streams = active_streams.clone();
+                let peer_addrs = peer_addrs.clone();
+                let address_book = address_book.clone();
+                let hello = hello.clone();
+                let retry_policy = retry_policy.clone();
+                let sequences = sequences.clone();
+                let on_event = on_event.clone();
+                let stop_signal = stop_signal.clone();
+                threads.push(spawn(move || {
+                    // the sequence starts over when the remote machine restarts
+                    let mut sequence = Sequence::new(reorder);
+                    let mut session = None;
+                    // the failed handshakes in a row, which count as failed attempts
+                    let mut failures = 0;
+                    while let Some((tcp_stream, peer_addr)) =
+                            connect(&remote_addr, &retry_policy, &stop_signal) {
+                        // registered before the hello message is sent, so that it is known
+                        // by the time the remote machine subscribes back
+                        write_lock(&peer_addrs).insert(peer_addr);
+                        // tell the remote machine who we are, where to subscribe back and
+                        // where to resume from, and learn who it is
+                        let hello = Hello {
+                            resume: session.and_then(|session| {
+                                sequence.resume_point().map(|seq| Resume { session, seq })
+                            }),
+                            ..hello.clone()
                         };
+                        let mut stream = BufStream::new(tcp_stream);
+                        let handshake = handshake(&remote_addr, &mut stream, &hello,
+                                                  &socket_options);
+                        let (sender_name, replay_gap) = match handshake {
+                            Ok((reply, protocol)) => {
+                                info!("Remote machine {} speaks the protocol version {} \
+                                       with the features {:?}.",
+                                      remote_addr, protocol.version, protocol.features);
+                                let name = reply.node_id
+                                    .unwrap_or_else(|| peer_addr.to_string());
+                                // the packets held from the previous session of the remote
+                                // machine are delivered before its sequence starts over
+                                if session.replace(reply.session) != Some(reply.session) {
+                                    let packets = {
+                                        let mut sequences = write_lock(&sequences);
+                                        let stats = sequences.entry(name.clone()).or_default();
+                                        sequence.flush(stats)
+                                    };
+                                    deliver(&callback, &name, packets);
+                                    sequence = Sequence::new(reorder);
+                                } else if reply.restarted {
+                                    info!("Remote machine {} numbers the packets anew.",
+                                          remote_addr);
+                                    let packets = {
+                                        let mut sequences = write_lock(&sequences);
+                                        let stats = sequences.entry(name.clone()).or_default();
+                                        sequence.restart(stats)
+                                    };
+                                    deliver(&callback, &name, packets);
+                                }
+                                failures = 0;
+                                (name, reply.replay_gap)
+                            },
+                            // an incompatible machine stays incompatible, so it is not retried
+                            Err(err @ TmsnError::IncompatiblePeer(..)) => {
+                                error!("{}. Giving up.", err);
+                                write_lock(&peer_addrs).remove(&peer_addr);
+                                break;
+                            },
+                            Err(err) => {
+                                error!("(attempt {}) {}", failures + 1, err);
+                                write_lock(&peer_addrs).remove(&peer_addr);
+                                failures += 1;
+                                if !retry_policy.should_retry(failures) {
+                                    break;
+                                }
+                                sleep_or_stop(retry_policy.get_delay(failures), &stop_signal);
+                                continue;
+                            },
+                        };
+                        {
+                            let mut address_book = write_lock(&address_book);
+                            address_book.insert(remote_addr.clone(), sender_name.clone());
+                            address_book.insert(RemoteAddr::Ip(peer_addr), sender_name.clone());
+                        }
+                        // the packets missed while disconnected are replayed, unless the remote
+                        // machine no longer keeps them
+                        if let Some(seq) = replay_gap {
+                            error!("Remote machine {} cannot replay all the packets missed.",
+                                   remote_addr);
+                            let packets = {
+                                let mut sequences = write_lock(&sequences);
+                                let stats = sequences.entry(sender_name.clone()).or_default();
+                                sequence.skip(seq, stats)
+                            };
+                            deliver(&callback, &sender_name, packets);
+                        }
+                        if let Ok(stream_copy) = stream.get_ref().try_clone() {
+                            write_lock(&active_streams).insert(remote_addr.clone(), stream_copy);
+                        }
+                        // check again in case the network was stopped before the stream was
+                        // registered, in which case it would never be closed
+                        if is_stopped(&stop_signal) {
+                            break;
+                        }
+                        on_event(PeerEvent::PeerConnected(
+                            sender_name.clone(), Connection::Subscription));
+                        receiver(peer_addr, sender_name.clone(), stream, receipts.as_ref(),
+                                 callback.clone(), &mut sequence, &sequences);
+                        write_lock(&active_streams).remove(&remote_addr);
+                        write_lock(&peer_addrs).remove(&peer_addr);
+                        if is_stopped(&stop_signal) {
+                            break;
+                        }
+                        on_event(PeerEvent::PeerDisconnected(
+                            sender_name, Connection::Subscription));
+                        info!("Lost the connection to {}. Reconnecting.", remote_addr);
                     }
-                    if tcp_stream.is_some() {
-                        let stream = BufStream::new(tcp_stream.unwrap());
-                        receiver(addr, stream, outbound, callback);
-                    } else {
-                        info!("Failed to connect to remote address {}. Quit.", remote_addr);
-                    }
-                });
+                    info!("Receiver for remote address {} has quit.", remote_addr);
+                    write_lock(&receivers).remove(&remote_addr);
+                }));
             } else {
                 info!("(Skipped) Receiver exists for {}", remote_addr);
             }
         }
+
+        // close all streams to unblock the receivers
+        read_lock(&active_streams).values().for_each(|stream| {
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        threads.into_iter().for_each(|thread| {
+            if thread.join().is_err() {
+                error!("A receiver thread panicked.");
+            }
+        });
+        info!("Receivers listener has stopped.");
     });
+    (subscriptions, handle)
 }
 
 
-// Core receiver routine
+// Connect to the remote address, retrying according to the retry policy.
+// The host name of the remote address is resolved again on every attempt.
+// Returns the stream and the IP address it is connected to, or `None` if all attempts failed
+// or the network was stopped.
+fn connect(
+    remote_addr: &RemoteAddr, retry_policy: &RetryPolicy, stop_signal: &StopSignal,
+) -> Option<(TcpStream, SocketAddr)> {
+    // an unreachable machine holds up the attempt no longer than the retry delay,
+    // so that the network can be stopped in the meantime
+    let timeout = retry_policy.initial_delay.max(POLL_INTERVAL);
+    let mut attempts = 0;
+    while !is_stopped(stop_signal) {
+        attempts += 1;
+        let connection = remote_addr.connect(timeout, stop_signal)
+            .and_then(|stream| stream.peer_addr().map(|peer_addr| (stream, peer_addr)));
+        match connection {
+            Ok(connection) => return Some(connection),
+            Err(error) => {
+                info!("(attempt {}) Error: {}. Failed to connect to remote address {}",
+                      attempts, error, remote_addr);
+            }
+        }
+        if !retry_policy.should_retry(attempts) {
+            return None;
+        }
+        let delay = retry_policy.get_delay(attempts);
+        info!("Retry connecting to {} in {:?}", remote_addr, delay);
+        sleep_or_stop(delay, stop_signal);
+    }
+    None
+}
+
+
+// Set up the connection, exchange the hello messages with the remote machine,
+// and agree on the protocol
+fn handshake(
+    remote_addr: &RemoteAddr, stream: &mut BufStream<TcpStream>, hello: &Hello,
+    socket_options: &SocketOptions,
+) -> Result<(Hello, Protocol), TmsnError> {
+    let reply = socket_options.apply(stream.get_ref())
+        .and_then(|_| write_hello(stream, hello))
+        .and_then(|_| read_reply(stream, socket_options.read_timeout))
+        .map_err(|err| TmsnError::Connect(remote_addr.to_string(), err))?;
+    let protocol = hello.negotiate(&reply)
+        .map_err(|reason| TmsnError::IncompatiblePeer(remote_addr.to_string(), reason))?;
+    Ok((reply, protocol))
+}
+
+
+// Read the hello message the remote machine replies with.
+//
+// The older versions of tmsn send no reply, in which case an empty hello message is returned
+// after `HELLO_TIMEOUT`, or as soon as the first packet arrives.
+// The read timeout is then set to `read_timeout`.
+fn read_reply(
+    stream: &mut BufStream<TcpStream>, read_timeout: Option<Duration>,
+) -> io::Result<Hello> {
+    stream.get_ref().set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let reply = match is_hello(stream) {
+        Ok(true) => read_hello(stream)?,
+        Ok(false) => Hello::default(),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                        err.kind() == ErrorKind::TimedOut => Hello::default(),
+        Err(err) => return Err(err),
+    };
+    stream.get_ref().set_read_timeout(read_timeout)?;
+    Ok(reply)
+}
+
+
+// Core receiver routine, returns when the connection is closed or broken
 pub fn receiver(
-    remote_ip: SocketAddr, mut stream: BufStream<TcpStream>,
-    outbound_send: Sender<(Option<String>, Packet)>,
-    callback: Arc<RwLock<Box<dyn FnMut(String, Packet) + Sync + Send>>>,
+    remote_ip: SocketAddr, sender_name: String, mut stream: BufStream<TcpStream>,
+    receipts: Option<&Receipts>,
+    callback: Arc<RwLock<PacketCallback>>,
+    sequence: &mut Sequence,
+    sequences: &SequenceBook,
 ) {
-    let remote_ip_str = remote_ip.ip().to_string();
-    info!("Receiver started, {}, {}", remote_ip, remote_ip_str);
+    info!("Receiver started, {} ({})", sender_name, remote_ip);
     let mut idx = 0;
     loop {
-        let mut json = String::new();
-        let read_result = stream.read_line(&mut json);
-        if let Err(_) = read_result {
-            error!("Cannot read the remote model from network.");
-            continue;
+        // the packets held for a missing packet are delivered once it is no longer waited for,
+        // even if nothing else arrives
+        if let Some(deadline) = sequence.hold_deadline() {
+            match wait_for_frame(&mut stream, deadline) {
+                Ok(true) => {},
+                Ok(false) => {
+                    let packets = {
+                        let mut sequences = write_lock(sequences);
+                        let stats = sequences.entry(sender_name.clone()).or_default();
+                        sequence.expire(stats)
+                    };
+                    deliver(&callback, &sender_name, packets);
+                    continue;
+                },
+                Err(err) => {
+                    error!("Cannot read the remote packet from {}. Message ID {}. Error: {}",
+                           remote_ip, idx, err);
+                    return;
+                },
+            }
         }
-
-        if json.trim().len() != 0 {
-            let remote_packet = serde_json::from_str(&json);
-            if let Err(err) = remote_packet {
-                error!("Cannot parse the JSON description of the remote model from {}. \
-                        Message ID {}, JSON string is `{}`. Error: {}", remote_ip, idx, json, err);
-            } else {
-                let sender_name = remote_ip_str.clone();
-                let (remote_idx, mut packet): JsonFormat = remote_packet.unwrap();
-                trace!("message-received, {}, {}, {}, {}, {}",
-                       idx, sender_name, remote_idx, remote_ip, json.len());
+        match read_frame(&mut stream) {
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                            err.kind() == ErrorKind::TimedOut => {
+                error!("Received nothing from {} before the read timeout, message ID {}.",
+                       remote_ip, idx);
+                return;
+            },
+            Err(err) => {
+                // the position in the stream is unknown after a failed read, so
+                // the connection cannot be used any more
+                error!("Cannot read the remote packet from {}. Message ID {}. Error: {}",
+                       remote_ip, idx, err);
+                return;
+            },
+            Ok(None) => {
+                info!("Remote address {} closed the connection, message ID {}", remote_ip, idx);
+                return;
+            },
+            Ok(Some((remote_idx, mut packet))) => {
+                trace!("message-received, {}, {}, {}, {}, {}", idx, sender_name, remote_idx,
+                       remote_ip, packet.content.len());
                 packet.mark_received();
-                let f = &mut *(callback.write().unwrap());
+                // the duplicated packets are acknowledged again, but not delivered again
                 let receipt = packet.get_receipt();
-                f(sender_name.clone(), packet);
-                if receipt.is_some() {
-                    outbound_send.send((Some(sender_name), receipt.unwrap())).unwrap();
+                let packets = {
+                    let mut sequences = write_lock(sequences);
+                    let stats = sequences.entry(sender_name.clone()).or_default();
+                    sequence.receive(packet, stats)
+                };
+                deliver(&callback, &sender_name, packets);
+                if let (Some(receipts), Some(receipt)) = (receipts, receipt) {
+                    if let Err(err) = receipts.send(&sender_name, receipt) {
+                        trace!("Cannot send out the receipt to {}. Error: {}", remote_ip, err);
+                    }
                 }
-            }
-            idx += 1;
-        } else {
-            trace!("Received an empty message from {}, message ID {}", remote_ip, idx)
+                idx += 1;
+            },
         }
     }
 }
+
+
+// Wait until the next frame starts arriving, or until `deadline`, without reading it.
+// Returns `false` if nothing arrived by then.
+fn wait_for_frame(stream: &mut BufStream<TcpStream>, deadline: Instant) -> io::Result<bool> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Ok(false);
+    }
+    let read_timeout = stream.get_ref().read_timeout()?;
+    stream.get_ref().set_read_timeout(Some(timeout))?;
+    // nothing is taken from the buffer, so the frame is read as a whole afterwards
+    let arrived = match stream.fill_buf() {
+        Ok(_) => Ok(true),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                        err.kind() == ErrorKind::TimedOut ||
+                        err.kind() == ErrorKind::Interrupted => Ok(false),
+        Err(err) => Err(err),
+    };
+    stream.get_ref().set_read_timeout(read_timeout)?;
+    arrived
+}
+
+
+// Pass the packets received from `sender_name` to the callback
+fn deliver(callback: &Arc<RwLock<PacketCallback>>, sender_name: &str, packets: Vec<Packet>) {
+    if packets.is_empty() {
+        return;
+    }
+    let f = &mut *(write_lock(callback));
+    packets.into_iter().for_each(|packet| f(sender_name.to_string(), packet));
+}
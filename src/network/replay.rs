@@ -0,0 +1,85 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::sequence::precedes;
+
+
+// Packets sent to a remote machine, numbered in sequence, and kept so that they can be replayed
+// after the remote machine reconnects. The packets sent while it is disconnected are kept too,
+// until it has been gone for too long.
+pub struct ReplayLog {
+    // the sequence number of the next packet
+    next_seq: u32,
+    // the latest packets sent out, and their sequence numbers
+    frames: VecDeque<(u32, Arc<[u8]>)>,
+    // the maximum number of packets kept
+    capacity: usize,
+    // the number of bytes of the packets kept, and its maximum
+    num_bytes: usize,
+    max_bytes: usize,
+    // since when the remote machine has been disconnected, `None` while it is connected
+    departed: Option<Instant>,
+}
+
+
+impl ReplayLog {
+    pub fn new(capacity: usize, max_bytes: usize) -> ReplayLog {
+        ReplayLog {
+            next_seq: 0,
+            frames: VecDeque::new(),
+            capacity,
+            num_bytes: 0,
+            max_bytes,
+            departed: None,
+        }
+    }
+
+    // Keep a packet sent out, dropping the oldest packets if the log is full.
+    // Returns the sequence number of the packet.
+    pub fn record(&mut self, frame: Arc<[u8]>) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        if self.capacity == 0 || frame.len() > self.max_bytes {
+            // the packets before it could not be replayed without it either
+            self.frames.clear();
+            self.num_bytes = 0;
+            return seq;
+        }
+        while self.frames.len() >= self.capacity || self.num_bytes + frame.len() > self.max_bytes {
+            let (_, oldest) = self.frames.pop_front().unwrap();
+            self.num_bytes -= oldest.len();
+        }
+        self.num_bytes += frame.len();
+        self.frames.push_back((seq, frame));
+        seq
+    }
+
+    // Get the sequence number of the oldest packet kept, or of the next packet if none is kept
+    pub fn first_seq(&self) -> u32 {
+        self.frames.front().map(|(seq, _)| *seq).unwrap_or(self.next_seq)
+    }
+
+    // Get the packets kept starting from the sequence number `seq`
+    pub fn since(&self, seq: u32) -> Vec<(u32, Arc<[u8]>)> {
+        self.frames.iter()
+            .filter(|(s, _)| !precedes(*s, seq))
+            .cloned()
+            .collect()
+    }
+
+    // Record whether the remote machine is connected
+    pub fn set_connected(&mut self, connected: bool) {
+        if connected {
+            self.departed = None;
+        } else if self.departed.is_none() {
+            self.departed = Some(Instant::now());
+        }
+    }
+
+    // Check if the remote machine has been disconnected for at least `retention`
+    pub fn is_expired(&self, retention: Duration) -> bool {
+        self.departed.map(|departed| departed.elapsed() >= retention).unwrap_or(false)
+    }
+}
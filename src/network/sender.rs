@@ -1,158 +1,636 @@
 use bufstream::BufStream;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
 use std::io::Write;
+use std::net::IpAddr;
+use std::net::Shutdown;
 use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::thread::sleep;
 use std::thread::spawn;
+use std::time::Duration;
 
-use packet::JsonFormat;
+use error::TmsnError;
+use liveness::Connection;
+use liveness::PeerEvent;
 use packet::Packet;
+use packet::WireFormat;
+use queue::OutboundQueue;
+use super::AddressBook;
+use super::HELLO_TIMEOUT;
+use super::POLL_INTERVAL;
+use super::PeerQueueOptions;
+use super::SocketOptions;
+use super::RemoteAddr;
+use super::StopSignal;
+use super::frame::Hello;
+use super::frame::Protocol;
+use super::frame::Resume;
+use super::frame::SEQ_FEATURE;
+use super::frame::read_frame;
+use super::frame::read_hello;
+use super::frame::encode_frame;
+use super::frame::write_encoded_frame;
+use super::frame::write_hello;
+use super::is_stopped;
+use super::replay::ReplayLog;
+use super::sequence::precedes;
+use super::read_lock;
+use super::write_lock;
 
 use HEAD_NODE;
 use LockedStream;
+use EventCallback;
+use Outgoing;
+
+
+// Packets sent to each remote machine, kept to be replayed after it reconnects, by its node ID
+type ReplayLogs = Arc<RwLock<HashMap<String, ReplayLog>>>;
+
+
+/// A remote machine subscribing to this machine, with the queue of the packets
+/// waiting to be written to it
+pub struct Subscriber {
+    /// the node ID of the remote machine
+    pub name: String,
+    // the sequence numbers and the encoded frames, which are shared by all subscribers
+    queue: OutboundQueue<(u32, Arc<[u8]>)>,
+    // returns the frames that were not written out
+    writer: JoinHandle<Vec<(u32, Arc<[u8]>)>>,
+    // to close the stream of a subscriber that has fallen too far behind
+    stream: TcpStream,
+    // if the subscriber keeps its name when it reconnects, so that the packets sent to it
+    // are kept to be replayed
+    resumable: bool,
+    // if the subscriber runs an older version of tmsn, which only understands the JSON frames
+    legacy: bool,
+}
+
+
+impl Subscriber {
+    // Start the writer thread of a new subscriber that speaks `protocol`
+    fn new(
+        name: String, stream: TcpStream, options: PeerQueueOptions, protocol: &Protocol,
+        resumable: bool,
+    ) -> io::Result<Subscriber> {
+        let sequenced = protocol.features.iter().any(|feature| feature == SEQ_FEATURE);
+        let legacy = protocol.version == 0;
+        let queue = OutboundQueue::new(
+            Some(options.capacity), options.slow_peer_policy.queue_policy());
+        let writer = {
+            let name = name.clone();
+            let stream = BufStream::new(stream.try_clone()?);
+            let queue = queue.clone();
+            spawn(move|| writer(name, stream, queue, sequenced))
+        };
+        Ok(Subscriber { name, queue, writer, stream, resumable, legacy })
+    }
+
+    /// Get the number of packets waiting to be written to the subscriber
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Get the number of packets dropped because the subscriber fell too far behind
+    pub fn num_dropped(&self) -> usize {
+        self.queue.num_dropped()
+    }
+
+    // Close the stream, and stop the writer without writing out the rest of the queue.
+    // Returns the frames that were not written out.
+    fn disconnect(&self) -> Vec<(u32, Arc<[u8]>)> {
+        self.queue.close();
+        let frames = self.queue.drain();
+        let _ = self.stream.shutdown(Shutdown::Both);
+        frames
+    }
+
+    // Wait for the writer to exit, and return the packets that were not written out
+    fn join(self) -> Vec<Outgoing> {
+        let name = self.name;
+        let mut frames = self.writer.join().unwrap_or_else(|_| {
+            error!("The writer thread to {} panicked.", name);
+            vec![]
+        });
+        frames.extend(self.queue.drain());
+        decode_frames(&name, frames)
+    }
+}
+
+
+// Decode the frames that were not written out to the subscriber `name`
+fn decode_frames(name: &str, frames: Vec<(u32, Arc<[u8]>)>) -> Vec<Outgoing> {
+    frames.into_iter()
+        .filter_map(|(_, frame)| match read_frame(&mut &frame[..]) {
+            Ok(Some((_, packet))) => Some((Some(name.to_string()), packet)),
+            _ => None,
+        })
+        .collect()
+}
 
 
 // Start all sender routines - start local sender and also accept remote senders
+//
+// `port` can be 0 for a port assigned by the OS. Returns the address that is actually bound.
+// The returned thread exits after the network is stopped, and returns the packets
+// that could not be delivered.
+#[allow(clippy::too_many_arguments)]
 pub fn start_sender(
+    bind_addr: IpAddr,
     port: u16,
-    packet_recv: Receiver<(Option<String>, Packet)>,
-    remote_ip_send: Option<Sender<SocketAddr>>,
-) -> Result<LockedStream, &'static str> {
-    // Vec<BufStream<TcpStream>>
+    node_id: Option<String>,
+    packet_recv: OutboundQueue,
+    remote_ip_send: Option<Sender<RemoteAddr>>,
+    address_book: AddressBook,
+    wire_format: Arc<RwLock<WireFormat>>,
+    peer_queue: PeerQueueOptions,
+    socket_options: SocketOptions,
+    on_event: EventCallback,
+    stop_signal: StopSignal,
+) -> Result<(LockedStream, JoinHandle<Vec<Outgoing>>, SocketAddr), TmsnError> {
+    // Vec<Subscriber>
     let streams = Arc::new(RwLock::new(vec![]));
     // accepts remote connections
-    let listener = {
-        let local_addr: SocketAddr =
-            (String::from("0.0.0.0:") + port.to_string().as_str()).parse().expect(
-                &format!("Cannot parse the port number `{}`.", port)
-            );
-        let listener = TcpListener::bind(local_addr);
-        if listener.is_err() {
-            return Err("Failed to bind the listening port");
-        }
-        listener.unwrap()
-    };
+    let local_addr = SocketAddr::new(bind_addr, port);
+    let listener = TcpListener::bind(local_addr)
+        .map_err(|err| TmsnError::Bind(local_addr.to_string(), err))?;
+    // the listener is polled so that it can be closed when the network is stopped
+    listener.set_nonblocking(true)
+        .map_err(|err| TmsnError::Bind(local_addr.to_string(), err))?;
+    let local_addr = listener.local_addr()
+        .map_err(|err| TmsnError::Bind(local_addr.to_string(), err))?;
+    let hello = Hello::new(node_id, Some(local_addr.port()));
     let streams_clone = streams.clone();
     // sender will be started inside income_conn_listener
-    spawn(move|| {
-        income_conn_listener(streams_clone, remote_ip_send, listener, packet_recv);
+    let handle = spawn(move|| {
+        income_conn_listener(streams_clone, remote_ip_send, address_book, hello, listener,
+                             packet_recv, wire_format, peer_queue, socket_options, on_event,
+                             stop_signal)
     });
-    Ok(streams)
+    Ok((streams, handle, local_addr))
 }
 
 
 // Sender listener (i.e. the listener of the sender) is responsible for:
 //     1. Add new incoming stream to sender (via streams RwLock)
 //     2. Send new incoming address to receiver so that it connects to the new machine
+#[allow(clippy::too_many_arguments)]
 fn income_conn_listener(
     sender_streams: LockedStream,
-    receiver_ips: Option<Sender<SocketAddr>>,
+    receiver_ips: Option<Sender<RemoteAddr>>,
+    address_book: AddressBook,
+    hello: Hello,
     listener: TcpListener,
-    packet_recv: Receiver<(Option<String>, Packet)>,
-) {
-    let process_stream = |stream: TcpStream| {
-        let remote_addr = stream.peer_addr().expect(
-            "Cannot unwrap the remote address from the incoming stream."
-        );
-        let local_addr = stream.local_addr().expect(
-            "Cannot unwrap the local address from the incoming stream.");
-        info!("Sender received a connection, {}, ->, {}", remote_addr, local_addr);
-        // append the new stream to sender
-        let mut lock_w = sender_streams.write().expect(
-            "Failed to obtain the lock for expanding sender_streams."
-        );
-        let remote_addr_str = remote_addr.ip().to_string();
-        lock_w.push((remote_addr_str.clone(), BufStream::new(stream)));
-        drop(lock_w);
-        info!("Remote server {} will receive our model from now on.", remote_addr_str);
-        // subscribe to the remote machine
-        if let Some(ref receivers) = receiver_ips {
-            receivers.send(remote_addr.clone()).expect(
-                "Cannot send the received IP to the channel."
-            );
-            info!("Remote server {} will be subscribed soon.", remote_addr);
-        }
-    };
+    packet_recv: OutboundQueue,
+    wire_format: Arc<RwLock<WireFormat>>,
+    peer_queue: PeerQueueOptions,
+    socket_options: SocketOptions,
+    on_event: EventCallback,
+    stop_signal: StopSignal,
+) -> Vec<Outgoing> {
+    let local_port = hello.port.unwrap_or_default();
 
+    // the sender is started after the first stream is added,
+    // so that the packets sent out before that are kept in the queue
     info!("Processing first connection");
-    let mut local_addr = None;
-    while local_addr.is_none() {
-        local_addr = match listener.accept() {
+    let mut packet_recv = Some(packet_recv);
+    let mut sender_thread = None;
+    let mut handshake_threads = vec![];
+    let replay_logs: ReplayLogs = Arc::new(RwLock::new(HashMap::new()));
+    while !is_stopped(&stop_signal) {
+        match listener.accept() {
             Ok((stream, _addr)) => {
-                let local_addr = stream.local_addr().expect(
-                    "Cannot unwrap the local address from the incoming stream.");
-                process_stream(stream);
-                Some(local_addr)
-            }
-            Err(e) => {
-                error!("Couldn't get client during the initialization: {:?}", e);
-                None
-            }
-        };
+                if let Err(err) = stream.set_nonblocking(false) {
+                    error!("Couldn't set up the incoming connection. Error: {}", err);
+                    continue;
+                }
+                let sender_streams = sender_streams.clone();
+                let receiver_ips = receiver_ips.clone();
+                let address_book = address_book.clone();
+                let hello = hello.clone();
+                let replay_logs = replay_logs.clone();
+                let on_event = on_event.clone();
+                let stop_signal = stop_signal.clone();
+                handshake_threads.push(spawn(move|| {
+                    process_stream(stream, local_port, &hello, &sender_streams, &receiver_ips,
+                                   &address_book, &replay_logs, peer_queue, socket_options,
+                                   &on_event, &stop_signal);
+                }));
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+            Err(e) => error!("Sender received an error connection: {:?}", e),
+        }
+        if sender_thread.is_none() && !read_lock(&sender_streams).is_empty() {
+            let streams = sender_streams.clone();
+            let local_addr = listener.local_addr()
+                .map(|local_addr| local_addr.to_string())
+                .unwrap_or_default();
+            let packet_recv = packet_recv.take().unwrap();
+            let wire_format = wire_format.clone();
+            let on_event = on_event.clone();
+            let replay_logs = replay_logs.clone();
+            let stop_signal = stop_signal.clone();
+            sender_thread = Some(spawn(move|| {
+                sender(local_addr, streams, packet_recv, wire_format, replay_logs, peer_queue,
+                       on_event, stop_signal)
+            }));
+            info!("Entering sender listening mode");
+        }
+        handshake_threads.retain(|thread| !thread.is_finished());
     }
-    let streams = sender_streams.clone();
-    let local_addr = local_addr.unwrap().ip().to_string();
-    spawn(move|| {
-        sender(local_addr, streams, packet_recv);
+
+    // unbind the listening port
+    drop(listener);
+    handshake_threads.into_iter().for_each(|thread| {
+        if thread.join().is_err() {
+            error!("A handshake thread panicked.");
+        }
     });
+    info!("Sender listener has stopped.");
+    match sender_thread {
+        Some(sender_thread) => sender_thread.join().unwrap_or_else(|_| {
+            error!("The sender thread panicked.");
+            vec![]
+        }),
+        // nobody ever connected, so none of the packets was sent out
+        None => packet_recv.unwrap().drain(),
+    }
+}
+
 
-    info!("Entering sender listening mode");
-    for stream in listener.incoming() {
-        match stream {
-            Err(_) => error!("Sender received an error connection."),
-            Ok(stream) => process_stream(stream),
+// Learn the node ID and the listening port of the remote machine from its hello message,
+// reply with the hello message of this machine, then add the stream to the sender with
+// its own writer thread, report it via `on_event`, and subscribe to the remote machine.
+//
+// A remote machine that reconnects gets the packets it missed replayed first, if they are
+// still kept in `replay_logs`. Otherwise the reply tells it where the replay starts.
+//
+// The older versions of tmsn send no hello message, in which case the remote machine is
+// named by its address, and assumed to listen on the same port as this machine.
+#[allow(clippy::too_many_arguments)]
+fn process_stream(
+    stream: TcpStream, local_port: u16, local_hello: &Hello, sender_streams: &LockedStream,
+    receiver_ips: &Option<Sender<RemoteAddr>>, address_book: &AddressBook,
+    replay_logs: &ReplayLogs, peer_queue: PeerQueueOptions, socket_options: SocketOptions,
+    on_event: &EventCallback, stop_signal: &StopSignal,
+) {
+    let remote_addr = match stream.peer_addr() {
+        Ok(remote_addr) => remote_addr,
+        Err(err) => {
+            error!("Cannot get the address of the incoming stream. Error: {}", err);
+            return;
+        },
+    };
+    info!("Sender received a connection from {}", remote_addr);
+    if let Err(err) = socket_options.apply(&stream) {
+        error!("Cannot set up the connection from {}. Error: {}", remote_addr, err);
+        return;
+    }
+    let hello = stream.set_read_timeout(Some(HELLO_TIMEOUT))
+        .and_then(|_| read_hello(&mut &stream));
+    if let Err(err) = stream.set_read_timeout(socket_options.read_timeout) {
+        error!("Cannot set up the connection from {}. Error: {}", remote_addr, err);
+        return;
+    }
+    let (hello, replied) = match hello {
+        Ok(hello) => (hello, true),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                        err.kind() == ErrorKind::TimedOut => {
+            info!("No hello message from {}, assuming it listens on port {}.",
+                  remote_addr, local_port);
+            (Hello { port: Some(local_port), ..Hello::default() }, false)
+        },
+        Err(err) => {
+            error!("Cannot read the hello message from {}. Error: {}", remote_addr, err);
+            return;
+        },
+    };
+    let (node_id, listen_port) = (hello.node_id.clone(), hello.port);
+    // a machine that does not listen is addressed by the address of the connection
+    let addr = SocketAddr::new(remote_addr.ip(), listen_port.unwrap_or(remote_addr.port()));
+    let resumable = node_id.is_some() || listen_port.is_some();
+    let name = node_id.unwrap_or_else(|| addr.to_string());
+    // the sequence numbers are only resumed from within the same session of this machine
+    let resume = hello.resume.filter(|resume| resume.session == local_hello.session);
+    if replied {
+        // reply even if the protocols are incompatible, so that the remote machine
+        // learns the reason of the refusal
+        let reply = Hello {
+            replay_gap: resume.and_then(|resume| get_replay_gap(replay_logs, &name, resume)),
+            restarted: resume.is_some() && !read_lock(replay_logs).contains_key(&name),
+            ..local_hello.clone()
+        };
+        if let Err(err) = write_hello(&mut &stream, &reply) {
+            error!("Cannot reply the hello message to {}. Error: {}", remote_addr, err);
+            return;
+        }
+    }
+    let protocol = match local_hello.negotiate(&hello) {
+        Ok(protocol) => protocol,
+        Err(reason) => {
+            // the stream is closed when it is dropped
+            error!("{}", TmsnError::IncompatiblePeer(remote_addr.to_string(), reason));
+            return;
+        },
+    };
+    info!("Remote machine {} speaks the protocol version {} with the features {:?}.",
+          remote_addr, protocol.version, protocol.features);
+    if listen_port.is_some() {
+        write_lock(address_book).insert(RemoteAddr::Ip(addr), name.clone());
+    }
+    if is_stopped(stop_signal) {
+        return;
+    }
+    // append the new stream to sender
+    let sequenced = protocol.features.iter().any(|feature| feature == SEQ_FEATURE);
+    let subscriber = match Subscriber::new(
+            name.clone(), stream, peer_queue, &protocol, resumable) {
+        Ok(subscriber) => subscriber,
+        Err(err) => {
+            error!("Cannot start the writer to {}. Error: {}", name, err);
+            return;
+        },
+    };
+    {
+        // no packet is sent out in the meantime, so the replay is followed by the new packets
+        let mut streams = write_lock(sender_streams);
+        let mut replay_logs = write_lock(replay_logs);
+        if let (Some(resume), true) = (resume, sequenced) {
+            replay(&subscriber, &replay_logs, resume);
+        }
+        if let Some(log) = replay_logs.get_mut(&name) {
+            log.set_connected(true);
+        }
+        streams.push(subscriber);
+    }
+    info!("Remote server {} ({}) will receive our model from now on.", name, addr);
+    on_event(PeerEvent::PeerConnected(name.clone(), Connection::Subscriber));
+    // subscribe to the remote machine
+    if let (Some(receivers), Some(_)) = (receiver_ips, listen_port) {
+        if receivers.send(RemoteAddr::Ip(addr)).is_err() {
+            error!("Cannot send the received address {} to the receivers listener.", addr);
+        } else {
+            info!("Remote server {} ({}) will be subscribed soon.", name, addr);
+        }
+    }
+}
+
+
+// Get the sequence number of the first packet kept for the remote machine `name`,
+// if the packets it asks to resume from are no longer kept
+fn get_replay_gap(replay_logs: &ReplayLogs, name: &str, resume: Resume) -> Option<u32> {
+    read_lock(replay_logs).get(name)
+        .map(|log| log.first_seq())
+        .filter(|first_seq| precedes(resume.seq, *first_seq))
+}
+
+
+// Hand the packets kept for a subscriber that reconnects to its queue,
+// starting from the packet it asks to resume from
+fn replay(subscriber: &Subscriber, replay_logs: &HashMap<String, ReplayLog>, resume: Resume) {
+    let frames = match replay_logs.get(&subscriber.name) {
+        Some(log) => log.since(resume.seq),
+        None => return,
+    };
+    info!("Replaying {} packets to {} starting from {}.",
+          frames.len(), subscriber.name, resume.seq);
+    for frame in frames {
+        if subscriber.queue.try_push(frame).is_err() {
+            error!("Cannot replay the packets to {}, disconnecting.", subscriber.name);
+            subscriber.disconnect();
+            return;
         }
     }
 }
 
 
 // Core sender routine - 1 to many
-fn sender(local_addr: String, streams: LockedStream, chan: Receiver<(Option<String>, Packet)>) {
+//
+// The packets taken from `chan` are encoded once, handed to the queues of their destinations,
+// and written out by the writer thread of each subscriber, so that a slow subscriber only
+// holds up its own packets. The packets to each remote machine are numbered in sequence,
+// also across its reconnections, and the latest of them, as many as `peer_queue` allows, are
+// kept in `replay_logs`, including those sent while it is disconnected. A subscriber whose
+// writer has exited is removed from `streams`, and reported via `on_event`. The remote machine
+// is added back once it connects again, unless it has been gone for longer than the retention
+// of `peer_queue`, in which case the packets kept for it are dropped.
+//
+// Once the network is stopped, the packets still in the queue are handed to the subscribers,
+// the writers write out their queues and close the streams, and the packets that reached none
+// of their destinations are returned, along with the packets that were not written out to
+// a subscriber, addressed to it.
+#[allow(clippy::too_many_arguments)]
+fn sender(
+    local_addr: String, streams: LockedStream, chan: OutboundQueue,
+    wire_format: Arc<RwLock<WireFormat>>, replay_logs: ReplayLogs, peer_queue: PeerQueueOptions,
+    on_event: EventCallback, stop_signal: StopSignal,
+) -> Vec<Outgoing> {
     info!("1-to-many Sender has started, {}.", local_addr);
 
-    let mut idx = 0;
-    loop {
-        let data = chan.recv();
-        if let Err(err) = data {
-            error!("Network module cannot receive the local model. Error: {}", err);
-            continue;
+    while !is_stopped(&stop_signal) {
+        if let Some((remote_ip, data)) = chan.pop_timeout(POLL_INTERVAL) {
+            // the packets dropped with a subscriber that fell behind are replayed if it
+            // reconnects, as far as they are kept
+            send_packet(&local_addr, &streams, &wire_format, &replay_logs, &peer_queue,
+                        &remote_ip, &data, &mut vec![]);
+        }
+        remove_dead_subscribers(&streams, &replay_logs, &on_event);
+        remove_expired_logs(&replay_logs, peer_queue.replay_retention);
+    }
+
+    // flush the pending packets
+    let mut undelivered = vec![];
+    for (remote_ip, data) in chan.drain() {
+        let num_computers = send_packet(&local_addr, &streams, &wire_format, &replay_logs,
+                                        &peer_queue, &remote_ip, &data, &mut undelivered);
+        if num_computers == 0 {
+            undelivered.push((remote_ip, data));
         }
-        trace!("network-to-send-out, {}, {}", local_addr, idx);
-
-        let (remote_ip, data) = data.unwrap();
-        let num_computers = {
-            let streams = streams.write();
-            if let Err(err) = streams {
-                error!("Failed to obtain the lock for writing to sender_streams. Error: {}", err);
-                0
-            } else {
-                let mut streams = streams.unwrap();
-                let mut sent_out = 0;
-                streams.iter_mut().enumerate().for_each(|(index, (remote_addr, stream))| {
-                    if remote_ip.is_some() && remote_ip.as_ref().unwrap() != remote_addr &&
-                        (index != 0 || remote_ip.as_ref().unwrap() != &HEAD_NODE.to_string()) {
-                        return;
-                    }
-                    let packet_load: JsonFormat = (idx, data.clone());
-                    let json = serde_json::to_string(&packet_load).unwrap();
-                    if let Err(err) = stream.write_fmt(format_args!("{}\n", json)) {
-                        error!("Cannot write into one of the streams. Error: {}", err);
-                    } else {
-                        if let Err(err) = stream.flush() {
-                            error!("Cannot flush one of the streams. Error: {}", err);
-                        } else {
-                            sent_out += 1;
-                        }
-                    }
-                });
-                sent_out
+    }
+    // the packets that never reached a subscriber, e.g. because it stopped reading,
+    // are undelivered to it
+    let subscribers: Vec<Subscriber> = write_lock(&streams).drain(..).collect();
+    subscribers.iter().for_each(|subscriber| subscriber.queue.close());
+    subscribers.into_iter().for_each(|subscriber| undelivered.extend(subscriber.join()));
+    info!("1-to-many Sender has stopped, {}, {} packets undelivered.",
+          local_addr, undelivered.len());
+    undelivered
+}
+
+
+// Encode a packet, hand the frame to the queues of its destinations,
+// and return the number of machines it was handed to.
+// The packet is encoded in `wire_format`, and also in JSON if any destination runs an older
+// version of tmsn, at most once in each format.
+// The ID of the packet is sent out as the index of the frame. A sequence number is used up
+// even if the packet is dropped, so that the remote machine can tell that it is lost.
+// The packet is also kept to be replayed, including for the destinations that are
+// disconnected. The packets dropped with the subscribers that have fallen too far behind are
+// added to `dropped`.
+#[allow(clippy::too_many_arguments)]
+fn send_packet(
+    local_addr: &str, streams: &LockedStream, wire_format: &Arc<RwLock<WireFormat>>,
+    replay_logs: &ReplayLogs, peer_queue: &PeerQueueOptions, remote_ip: &Option<String>,
+    data: &Packet, dropped: &mut Vec<Outgoing>,
+) -> usize {
+    let idx = data.id;
+    trace!("network-to-send-out, {}, {}", local_addr, idx);
+    let format = *read_lock(wire_format);
+    let mut frames = HashMap::new();
+    let mut get_frame = |format: WireFormat| {
+        frames.entry(format).or_insert_with(|| {
+            encode_frame(&format, idx, data).map_err(|err| {
+                error!("Cannot encode the packet {} in {:?}. Error: {}", idx, format, err);
+            }).ok()
+        }).clone()
+    };
+    let num_computers = {
+        // the writers take the packets from the queues,
+        // so the new subscribers can be added in the meantime
+        // and the packets are not replayed to the new subscribers in the meantime
+        let streams = read_lock(streams);
+        let mut replay_logs = write_lock(replay_logs);
+        // the sequence numbers of the packet, by the names of its destinations
+        let mut seqs = HashMap::new();
+        let mut sent_out = 0;
+        streams.iter().enumerate().for_each(|(index, subscriber)| {
+            if remote_ip.is_some() && remote_ip.as_ref().unwrap() != &subscriber.name &&
+                (index != 0 || remote_ip.as_ref().unwrap() != HEAD_NODE) {
+                return;
             }
+            let frame = match get_frame(if subscriber.legacy { WireFormat::Json } else { format }) {
+                Some(frame) => frame,
+                None => return,
+            };
+            // a subscriber that has reconnected before its old stream is removed
+            // receives the packet only once
+            let seq = *seqs.entry(subscriber.name.as_str()).or_insert_with(|| {
+                replay_logs.entry(subscriber.name.clone())
+                    .or_insert_with(|| {
+                        ReplayLog::new(peer_queue.replay_capacity, peer_queue.replay_max_bytes)
+                    })
+                    .record(frame.clone())
+            });
+            match subscriber.queue.try_push((seq, frame.clone())) {
+                Ok(_) => sent_out += 1,
+                Err(TmsnError::WouldBlock) => {
+                    error!("Remote server {} has fallen {} packets behind, disconnecting.",
+                           subscriber.name, subscriber.queue.len());
+                    let frames = subscriber.disconnect();
+                    dropped.extend(decode_frames(&subscriber.name, frames));
+                },
+                // the writer has exited, and the subscriber is about to be removed
+                Err(_) => {},
+            }
+        });
+        // the packets to the remote machines that are disconnected are replayed once they
+        // reconnect
+        let connected: HashSet<&str> = streams.iter()
+            .map(|subscriber| subscriber.name.as_str())
+            .collect();
+        replay_logs.iter_mut()
+            .filter(|(name, _)| !connected.contains(name.as_str()))
+            .filter(|(name, _)| remote_ip.is_none() || remote_ip.as_ref() == Some(*name))
+            .for_each(|(_, log)| {
+                if let Some(frame) = get_frame(format) {
+                    log.record(frame);
+                }
+            });
+        sent_out
+    };
+    trace!("network-sent-out, {}, {}, {}", local_addr, idx, num_computers);
+    num_computers
+}
+
+
+// Remove the subscribers whose writers have exited. The packets kept for a subscriber that
+// cannot be recognized once it reconnects are dropped.
+fn remove_dead_subscribers(
+    streams: &LockedStream, replay_logs: &ReplayLogs, on_event: &EventCallback,
+) {
+    if read_lock(streams).iter().all(|subscriber| !subscriber.writer.is_finished()) {
+        return;
+    }
+    let dead_subscribers: Vec<Subscriber> = {
+        let mut streams = write_lock(streams);
+        let (dead, alive) = streams.drain(..).partition(|s| s.writer.is_finished());
+        *streams = alive;
+        dead
+    };
+    dead_subscribers.into_iter().for_each(|subscriber| {
+        if subscriber.writer.join().is_err() {
+            error!("The writer thread to {} panicked.", subscriber.name);
+        }
+        info!("Remote server {} is disconnected, and removed from the subscribers.",
+              subscriber.name);
+        // unless the subscriber has reconnected already
+        let name = &subscriber.name;
+        if !read_lock(streams).iter().any(|s| &s.name == name) {
+            let mut replay_logs = write_lock(replay_logs);
+            if !subscriber.resumable {
+                replay_logs.remove(&subscriber.name);
+            } else if let Some(log) = replay_logs.get_mut(&subscriber.name) {
+                log.set_connected(false);
+            }
+        }
+        on_event(PeerEvent::PeerDisconnected(subscriber.name, Connection::Subscriber));
+    });
+}
+
+
+// Drop the packets kept for the remote machines that have been disconnected for `retention`
+fn remove_expired_logs(replay_logs: &ReplayLogs, retention: Duration) {
+    if read_lock(replay_logs).values().all(|log| !log.is_expired(retention)) {
+        return;
+    }
+    write_lock(replay_logs).retain(|name, log| {
+        if log.is_expired(retention) {
+            info!("Remote server {} has been gone for {:?}, dropping the packets kept for it.",
+                  name, retention);
+        }
+        !log.is_expired(retention)
+    });
+}
+
+
+// Writer routine of a subscriber - write out the packets in its queue until the stream breaks,
+// or the queue is closed and empty. The stream is closed when the writer exits.
+// Returns the frame that could not be written out, if the stream broke.
+fn writer(
+    name: String, mut stream: BufStream<TcpStream>, queue: OutboundQueue<(u32, Arc<[u8]>)>,
+    sequenced: bool,
+) -> Vec<(u32, Arc<[u8]>)> {
+    loop {
+        let (seq, frame) = match queue.pop_timeout(POLL_INTERVAL) {
+            Some(packet) => packet,
+            None if queue.is_closed() => break,
+            None => continue,
         };
-        trace!("network-sent-out, {}, {}, {}", local_addr, idx, num_computers);
-        idx += 1;
+        let sequence = if sequenced { Some(seq) } else { None };
+        if let Err(err) = write_encoded_frame(&mut stream, &frame, sequence) {
+            error!("Cannot write into the stream to {}. Error: {}", name, err);
+            queue.close();
+            return vec![(seq, frame)];
+        }
+        // a burst of packets is flushed at once
+        if queue.is_empty() {
+            if let Err(err) = stream.flush() {
+                error!("Cannot flush the stream to {}. Error: {}", name, err);
+                queue.close();
+                return vec![(seq, frame)];
+            }
+        }
+    }
+    if let Err(err) = stream.flush() {
+        error!("Cannot flush the stream to {} before closing. Error: {}", name, err);
     }
+    let _ = stream.get_ref().shutdown(Shutdown::Both);
+    vec![]
 }
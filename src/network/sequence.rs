@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use packet::Packet;
+use super::ReorderOptions;
+
+
+// The maximum number of missing packets remembered for a remote machine, which are among
+// the latest packets
+const MAX_MISSING: usize = 1024;
+
+// The maximum number of message IDs remembered for a remote machine
+const MAX_DELIVERED_IDS: usize = 1 << 16;
+
+
+/// Counts of the packets from a remote machine that were lost, duplicated or reordered
+#[derive(Clone, Debug, Default)]
+pub struct SequenceStats {
+    /// number of packets that never arrived
+    pub num_lost: usize,
+    /// number of packets that arrived more than once, or after they were considered lost
+    /// in the ordered mode, and were dropped. The retransmissions of the messages delivered
+    /// already are counted too.
+    pub num_duplicates: usize,
+    /// number of packets that arrived after a packet sent out later
+    pub num_reordered: usize,
+}
+
+
+// Sequence of the packets received from a remote machine, numbered by the remote machine.
+// It is kept across the reconnections, so that the packets lost or duplicated in between
+// are detected.
+pub struct Sequence {
+    // the sequence number of the next packet expected, `None` before the first packet
+    next: Option<u32>,
+    // the sequence numbers skipped, which might still arrive late
+    missing: BTreeSet<u32>,
+    // the packets held until the missing packets before them arrive
+    held: BTreeMap<u32, Packet>,
+    // how long the packets are held, `None` to deliver the packets as they arrive
+    reorder: Option<ReorderOptions>,
+    // since when the packets held have been waiting for the next packet
+    waiting_since: Option<Instant>,
+    // the IDs of the latest messages delivered, in the order of delivery, so that
+    // a retransmitted message is delivered once
+    delivered: VecDeque<u32>,
+    delivered_ids: HashSet<u32>,
+}
+
+
+impl Sequence {
+    pub fn new(reorder: Option<ReorderOptions>) -> Sequence {
+        Sequence {
+            next: None,
+            missing: BTreeSet::new(),
+            held: BTreeMap::new(),
+            reorder,
+            waiting_since: None,
+            delivered: VecDeque::new(),
+            delivered_ids: HashSet::new(),
+        }
+    }
+
+    // Receive a packet, and return the packets to be delivered.
+    // The lost, duplicated and reordered packets are counted in `stats`.
+    pub fn receive(&mut self, packet: Packet, stats: &mut SequenceStats) -> Vec<Packet> {
+        let packets = self.order(packet, stats);
+        self.drop_delivered(packets, stats)
+    }
+
+    // Put a packet in the sequence, and return the packets that are next in the sequence
+    fn order(&mut self, packet: Packet, stats: &mut SequenceStats) -> Vec<Packet> {
+        let seq = match packet.seq {
+            Some(seq) => seq,
+            // the remote machine does not number the packets
+            None => return vec![packet],
+        };
+        let next = *self.next.get_or_insert(seq);
+        if precedes(seq, next) {
+            if self.missing.remove(&seq) {
+                stats.num_lost -= 1;
+                stats.num_reordered += 1;
+                return vec![packet];
+            }
+            stats.num_duplicates += 1;
+            return vec![];
+        }
+        let reorder = match self.reorder {
+            Some(reorder) => reorder,
+            None => {
+                self.skip_to(seq, stats);
+                return vec![packet];
+            },
+        };
+        if self.held.insert(seq, packet).is_some() {
+            stats.num_duplicates += 1;
+        } else if seq == next && self.held.len() > 1 {
+            // the packets held for it were sent out later
+            stats.num_reordered += 1;
+        }
+        // give up on the next packet once too many packets are held, or once it has been
+        // waited for too long
+        if self.held.len() > reorder.window || self.is_expired() {
+            return self.give_up(stats);
+        }
+        self.release()
+    }
+
+    // Get the time after which the next packet is no longer waited for,
+    // `None` if no packet is held
+    pub fn hold_deadline(&self) -> Option<Instant> {
+        self.waiting_since.zip(self.reorder).map(|(since, reorder)| since + reorder.timeout)
+    }
+
+    // Give up on the next packet if it has been waited for too long, and return the packets
+    // held for it that can be delivered now
+    pub fn expire(&mut self, stats: &mut SequenceStats) -> Vec<Packet> {
+        if !self.is_expired() {
+            return vec![];
+        }
+        let packets = self.give_up(stats);
+        self.drop_delivered(packets, stats)
+    }
+
+    // Give up on all the missing packets, and return all the packets held, e.g. before
+    // the sequence starts over
+    pub fn flush(&mut self, stats: &mut SequenceStats) -> Vec<Packet> {
+        let mut packets = vec![];
+        while !self.held.is_empty() {
+            packets.extend(self.give_up(stats));
+        }
+        self.drop_delivered(packets, stats)
+    }
+
+    // Start the sequence over, because the remote machine numbers the packets anew.
+    // Returns the packets held. The messages delivered are still remembered, since they
+    // might be retransmitted in the new sequence.
+    pub fn restart(&mut self, stats: &mut SequenceStats) -> Vec<Packet> {
+        let packets = self.flush(stats);
+        self.next = None;
+        self.missing.clear();
+        packets
+    }
+
+    // Get the sequence number of the next packet expected, `None` before the first packet
+    pub fn resume_point(&self) -> Option<u32> {
+        self.next
+    }
+
+    // Give up on the packets before `seq` that have not arrived, because the remote machine
+    // no longer keeps them to be sent again. Returns the packets held for them.
+    pub fn skip(&mut self, seq: u32, stats: &mut SequenceStats) -> Vec<Packet> {
+        let next = match self.next {
+            Some(next) if precedes(next, seq) => next,
+            _ => return vec![],
+        };
+        info!("Packets {} to {} are lost, and will not be sent again.",
+              next, seq.wrapping_sub(1));
+        // the packets held are never before `next`
+        let mut skipped: Vec<u32> = self.held.keys()
+            .filter(|held| precedes(**held, seq))
+            .cloned()
+            .collect();
+        skipped.sort_by_key(|held| held.wrapping_sub(next));
+        stats.num_lost += seq.wrapping_sub(next) as usize - skipped.len();
+        let mut packets: Vec<Packet> = skipped.iter()
+            .filter_map(|held| self.held.remove(held))
+            .collect();
+        self.next = Some(seq);
+        packets.extend(self.release());
+        self.drop_delivered(packets, stats)
+    }
+
+    // Drop the messages delivered already, i.e. the retransmissions of a message whose
+    // acknowledgement was lost. Only the messages numbered in sequence are checked, because
+    // the remote machines that do not number the packets do not retransmit them either.
+    fn drop_delivered(&mut self, packets: Vec<Packet>, stats: &mut SequenceStats) -> Vec<Packet> {
+        packets.into_iter()
+            .filter(|packet| {
+                if !packet.is_workload() || packet.seq.is_none() {
+                    return true;
+                }
+                if !self.delivered_ids.insert(packet.id) {
+                    stats.num_duplicates += 1;
+                    return false;
+                }
+                self.delivered.push_back(packet.id);
+                if self.delivered.len() > MAX_DELIVERED_IDS {
+                    let id = self.delivered.pop_front().unwrap();
+                    self.delivered_ids.remove(&id);
+                }
+                true
+            })
+            .collect()
+    }
+
+    // Take the held packets that are next in the sequence
+    fn release(&mut self) -> Vec<Packet> {
+        let mut packets = vec![];
+        while let Some(packet) = self.held.remove(&self.next.unwrap()) {
+            packets.push(packet);
+            self.next = self.next.map(|next| next.wrapping_add(1));
+        }
+        // the packets still held start waiting for the new next packet
+        if self.held.is_empty() {
+            self.waiting_since = None;
+        } else if !packets.is_empty() || self.waiting_since.is_none() {
+            self.waiting_since = Some(Instant::now());
+        }
+        packets
+    }
+
+    // Give up on the packets before the first packet held, and take the held packets
+    // that are next in the sequence from there
+    fn give_up(&mut self, stats: &mut SequenceStats) -> Vec<Packet> {
+        let next = match self.next {
+            Some(next) => next,
+            None => return vec![],
+        };
+        let first = match self.held.keys().min_by_key(|held| held.wrapping_sub(next)) {
+            Some(first) => *first,
+            None => return vec![],
+        };
+        self.skip_to(first, stats);
+        self.next = Some(first);
+        self.release()
+    }
+
+    fn is_expired(&self) -> bool {
+        self.hold_deadline().map(|deadline| deadline <= Instant::now()).unwrap_or(false)
+    }
+
+    // Count the packets before `seq` that have not arrived as lost,
+    // and expect the packet after `seq` next
+    fn skip_to(&mut self, seq: u32, stats: &mut SequenceStats) {
+        let next = self.next.unwrap_or(seq);
+        self.next = Some(seq.wrapping_add(1));
+        if !precedes(next, seq) {
+            return;
+        }
+        info!("Packets {} to {} are missing.", next, seq.wrapping_sub(1));
+        let num_missing = seq.wrapping_sub(next);
+        stats.num_lost += num_missing as usize;
+        // in the ordered mode the skipped packets are not delivered even if they arrive
+        if self.reorder.is_none() {
+            // only the packets among the latest `MAX_MISSING` are remembered
+            let num_remembered = num_missing.min(MAX_MISSING as u32);
+            self.missing.retain(|missing| seq.wrapping_sub(*missing) <= MAX_MISSING as u32);
+            self.missing.extend((1..=num_remembered).map(|i| seq.wrapping_sub(i)));
+        }
+    }
+}
+
+
+// Check if the sequence number `a` comes before `b`, allowing the sequence numbers to wrap
+// around as long as they are less than 2^31 apart
+pub fn precedes(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use packet::Packet;
+    use super::ReorderOptions;
+    use super::Sequence;
+    use super::SequenceStats;
+
+    #[test]
+    fn test_retransmissions() {
+        // a retransmission carries the ID of the message under a new sequence number,
+        // and is dropped once the message has been delivered
+        let packet = Packet::new(b"message".to_vec());
+        let mut sequence = Sequence::new(None);
+        let mut stats = SequenceStats::default();
+        for seq in 0..2 {
+            let retransmission = Packet { seq: Some(seq), ..packet.clone() };
+            let delivered = sequence.receive(retransmission, &mut stats);
+            assert_eq!(delivered.len(), if seq == 0 { 1 } else { 0 });
+        }
+        assert_eq!(stats.num_duplicates, 1);
+        assert_eq!(stats.num_lost, 0);
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let packet = |seq: u32| Packet { seq: Some(seq), ..Packet::new(vec![]) };
+        let seqs = |packets: Vec<Packet>| -> Vec<u32> {
+            packets.into_iter().map(|p| p.seq.unwrap()).collect()
+        };
+
+        // the packets are delivered as they arrive, and the late ones are delivered once
+        let mut sequence = Sequence::new(None);
+        let mut stats = SequenceStats::default();
+        for (seq, num_delivered) in [(5, 1), (6, 1), (8, 1), (6, 0), (7, 1), (9, 1)] {
+            assert_eq!(sequence.receive(packet(seq), &mut stats).len(), num_delivered);
+        }
+        assert_eq!((stats.num_lost, stats.num_duplicates, stats.num_reordered), (0, 1, 1));
+        assert_eq!(sequence.resume_point(), Some(10));
+        // only the latest missing packets are waited for
+        sequence.receive(packet(3000), &mut stats);
+        assert_eq!(stats.num_lost, 2990);
+        assert!(sequence.receive(packet(10), &mut stats).is_empty());
+        assert_eq!(seqs(sequence.receive(packet(2999), &mut stats)), vec![2999]);
+        assert_eq!((stats.num_lost, stats.num_duplicates, stats.num_reordered), (2989, 2, 2));
+        // the packets given up on are skipped
+        assert!(sequence.skip(3005, &mut stats).is_empty());
+        assert_eq!(stats.num_lost, 2993);
+        assert_eq!(sequence.resume_point(), Some(3005));
+
+        // the packets are held until the packets before them arrive
+        let reorder = |window: usize| {
+            Some(ReorderOptions { window, timeout: Duration::from_millis(100) })
+        };
+        let mut sequence = Sequence::new(reorder(2));
+        let mut stats = SequenceStats::default();
+        assert_eq!(seqs(sequence.receive(packet(0), &mut stats)), vec![0]);
+        assert!(sequence.receive(packet(2), &mut stats).is_empty());
+        assert!(sequence.receive(packet(2), &mut stats).is_empty());
+        assert_eq!(seqs(sequence.receive(packet(1), &mut stats)), vec![1, 2]);
+        assert_eq!((stats.num_lost, stats.num_duplicates, stats.num_reordered), (0, 1, 1));
+        // until too many packets are held
+        assert!(sequence.receive(packet(5), &mut stats).is_empty());
+        assert!(sequence.receive(packet(6), &mut stats).is_empty());
+        assert_eq!(seqs(sequence.receive(packet(7), &mut stats)), vec![5, 6, 7]);
+        assert!(sequence.receive(packet(4), &mut stats).is_empty());
+        assert_eq!((stats.num_lost, stats.num_duplicates, stats.num_reordered), (2, 2, 1));
+        // or the packets missing will not be sent again
+        assert!(sequence.receive(packet(10), &mut stats).is_empty());
+        assert!(sequence.skip(9, &mut stats).is_empty());
+        assert_eq!(seqs(sequence.skip(11, &mut stats)), vec![10]);
+        assert_eq!(stats.num_lost, 4);
+        assert_eq!(sequence.resume_point(), Some(11));
+        // or the packets have been held for too long
+        assert!(sequence.receive(packet(12), &mut stats).is_empty());
+        assert!(sequence.expire(&mut stats).is_empty());
+        sleep(Duration::from_millis(150));
+        assert_eq!(seqs(sequence.expire(&mut stats)), vec![12]);
+        assert_eq!(sequence.hold_deadline(), None);
+        // or the sequence starts over
+        assert!(sequence.receive(packet(15), &mut stats).is_empty());
+        assert!(sequence.receive(packet(17), &mut stats).is_empty());
+        assert_eq!(seqs(sequence.flush(&mut stats)), vec![15, 17]);
+        assert_eq!(stats.num_lost, 8);
+
+        // the sequence numbers wrap around
+        let mut sequence = Sequence::new(reorder(4));
+        let mut stats = SequenceStats::default();
+        sequence.receive(packet(u32::MAX - 1), &mut stats);
+        assert!(sequence.receive(packet(0), &mut stats).is_empty());
+        assert_eq!(seqs(sequence.receive(packet(u32::MAX), &mut stats)), vec![u32::MAX, 0]);
+        assert!(sequence.receive(packet(u32::MAX), &mut stats).is_empty());
+        assert_eq!((stats.num_lost, stats.num_duplicates, stats.num_reordered), (0, 1, 1));
+        assert_eq!(sequence.resume_point(), Some(1));
+        // the sequence starts over when the remote machine numbers the packets anew
+        assert!(sequence.restart(&mut stats).is_empty());
+        assert_eq!(sequence.resume_point(), None);
+        assert_eq!(seqs(sequence.receive(packet(0), &mut stats)), vec![0]);
+    }
+}
@@ -1,11 +1,31 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::time::SystemTime;
 
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
 use PerfStats;
 
 
 // local machine name, Packet index, packet
 pub type JsonFormat = (u32, Packet);
 
+// ID of the next packet created on this machine
+static NEXT_PACKET_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Encoding of the packets on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    /// length-prefixed binary frames carrying the raw payload bytes (default)
+    #[default]
+    Binary,
+    /// newline-delimited JSON frames, for talking to the nodes running an older version
+    Json,
+}
+
 /// Types of packets
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PacketType {
@@ -23,33 +43,47 @@ pub enum PacketType {
 /// Packet 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Packet {
-    /// Actual workload of the packet
-    pub content: Option<String>,
+    /// Actual workload of the packet, encoded by the codec of the network
+    #[serde(with = "utf8_content")]
+    pub content: Vec<u8>,
     /// Packet sent out time
     pub sent_time: SystemTime,
     /// Packet receive time
     pub receive_time: Option<SystemTime>,
     /// Type of the packet
     pub packet_type: PacketType,
+    /// ID of the packet, unique among the packets created on the machine that sent it out.
+    /// It is carried as the index of the frame, and the echo of a message carries the ID of
+    /// the message as its content.
+    #[serde(skip)]
+    pub id: u32,
+    /// Sequence number of the packet among the packets the remote machine sent to this
+    /// machine, `None` if the remote machine does not number the packets
+    #[serde(skip)]
+    pub seq: Option<u32>,
 }
 
 
 impl Packet {
-    pub fn new(msg: String) -> Packet {
+    pub fn new(msg: Vec<u8>) -> Packet {
         Packet {
-            content: Some(msg),
+            content: msg,
             sent_time: SystemTime::now(),
             receive_time: None,
             packet_type: PacketType::Message,
+            id: next_packet_id(),
+            seq: None,
         }
     }
 
     pub fn get_hb(perf_stats: &PerfStats) -> Packet {
         Packet {
-            content: Some(perf_stats.to_json()),
+            content: perf_stats.to_json().into_bytes(),
             sent_time: SystemTime::now(),
             receive_time: None,
             packet_type: PacketType::Heartbeat,
+            id: next_packet_id(),
+            seq: None,
         }
     }
 
@@ -61,20 +95,32 @@ impl Packet {
         if !self.is_workload() && self.packet_type != PacketType::Heartbeat {
             return None;
         }
-        let echo_type =
+        let (echo_type, content) =
             if self.is_workload() {
-                PacketType::Echo
+                (PacketType::Echo, self.id.to_string().into_bytes())
             } else {
-                PacketType::HeartbeatEcho
+                (PacketType::HeartbeatEcho, vec![])
             };
         Some(Packet {
-            content: None,
-            sent_time: self.sent_time.clone(),
-            receive_time: self.receive_time.clone(),
+            content,
+            sent_time: self.sent_time,
+            receive_time: self.receive_time,
             packet_type: echo_type,
+            id: next_packet_id(),
+            seq: None,
         })
     }
 
+    /// Get the ID of the message acknowledged by this echo packet.
+    /// Returns `None` for the other packets, and for the echoes sent by the older versions
+    /// of tmsn, which carry no ID.
+    pub fn get_acked_id(&self) -> Option<u32> {
+        if self.packet_type != PacketType::Echo {
+            return None;
+        }
+        std::str::from_utf8(&self.content).ok().and_then(|id| id.parse().ok())
+    }
+
     pub fn is_workload(&self) -> bool {
         self.packet_type == PacketType::Message
     }
@@ -86,3 +132,41 @@ impl Packet {
             .as_micros()
     }
 }
+
+
+fn next_packet_id() -> u32 {
+    NEXT_PACKET_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+
+// The older versions of tmsn expect the content of a JSON frame to be a string,
+// so the content is written as a string when the packets are sent out in `WireFormat::Json`.
+// The content that is not valid UTF-8 is written as an array of bytes instead, which only
+// the current version of tmsn reads.
+mod utf8_content {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Content {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(content) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => content.serialize(serializer),
+        }
+    }
+
+    // The older versions of tmsn send out `null` as the content of the echo packets
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let content: Option<Content> = Option::deserialize(deserializer)?;
+        Ok(match content {
+            Some(Content::Text(s)) => s.into_bytes(),
+            Some(Content::Bytes(bytes)) => bytes,
+            None => vec![],
+        })
+    }
+}
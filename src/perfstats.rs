@@ -1,10 +1,13 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::time::SystemTime;
 
+use error::TmsnError;
 use packet::Packet;
 use packet::PacketType;
 
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct PerfStats {
     /// total number of packets received (messages + heartbeats + echos)
     pub total: usize,
@@ -22,6 +25,30 @@ pub struct PerfStats {
     pub hb_duration: u128,
     /// perf stats of other machines
     pub others: HashMap<String, PerfStats>,
+    /// total number of subscribers removed because their connections were broken
+    #[serde(default)]
+    pub num_disconnects: usize,
+    /// the last time each subscriber was removed because its connection was broken
+    #[serde(default)]
+    pub last_disconnects: HashMap<String, SystemTime>,
+    /// number of packets waiting in the outbound queue
+    #[serde(default)]
+    pub outbound_depth: usize,
+    /// number of packets waiting to be written to each subscriber
+    #[serde(default)]
+    pub queue_depths: HashMap<String, usize>,
+    /// number of packets dropped because each subscriber fell too far behind
+    #[serde(default)]
+    pub queue_dropped: HashMap<String, usize>,
+    /// number of packets from each remote machine that never arrived
+    #[serde(default)]
+    pub num_lost: HashMap<String, usize>,
+    /// number of packets from each remote machine that arrived more than once, and were dropped
+    #[serde(default)]
+    pub num_duplicates: HashMap<String, usize>,
+    /// number of packets from each remote machine that arrived after a packet sent out later
+    #[serde(default)]
+    pub num_reordered: HashMap<String, usize>,
 }
 
 
@@ -37,11 +64,19 @@ impl PerfStats {
             msg_duration: 0,
             hb_duration: 0,
             others: HashMap::new(),
+            num_disconnects: 0,
+            last_disconnects: HashMap::new(),
+            outbound_depth: 0,
+            queue_depths: HashMap::new(),
+            queue_dropped: HashMap::new(),
+            num_lost: HashMap::new(),
+            num_duplicates: HashMap::new(),
+            num_reordered: HashMap::new(),
         }
     }
 
-    pub fn from_json(json: &String) -> PerfStats {
-        serde_json::from_str(json).unwrap()
+    pub fn from_json(json: &[u8]) -> Result<PerfStats, TmsnError> {
+        serde_json::from_slice(json).map_err(|err| TmsnError::Deserialize(err.to_string()))
     }
 
     fn new_local(ps: &PerfStats) -> PerfStats {
@@ -54,6 +89,14 @@ impl PerfStats {
             msg_duration: ps.msg_duration,
             hb_duration: ps.hb_duration,
             others: HashMap::new(),
+            num_disconnects: ps.num_disconnects,
+            last_disconnects: HashMap::new(),
+            outbound_depth: ps.outbound_depth,
+            queue_depths: HashMap::new(),
+            queue_dropped: HashMap::new(),
+            num_lost: HashMap::new(),
+            num_duplicates: HashMap::new(),
+            num_reordered: HashMap::new(),
         }
     }
 
@@ -74,10 +117,12 @@ impl PerfStats {
             },
             PacketType::Heartbeat => {
                 self.num_hb += 1;
-                self.others.insert(
-                    name,
-                    PerfStats::from_json(packet.content.as_ref().unwrap())
-                );
+                match PerfStats::from_json(&packet.content) {
+                    Ok(ps) => {
+                        self.others.insert(name, ps);
+                    },
+                    Err(err) => error!("Cannot read the heartbeat from {}. Error: {}", name, err),
+                }
             },
             PacketType::HeartbeatEcho => {
                 self.hb_duration += packet.get_duration();
@@ -86,6 +131,12 @@ impl PerfStats {
         }
     }
 
+    /// record a subscriber that was removed because its connection was broken
+    pub fn record_disconnect(&mut self, name: String) {
+        self.num_disconnects += 1;
+        self.last_disconnects.insert(name, SystemTime::now());
+    }
+
     pub fn get_avg_roundtrip_time_msg(&self) -> f64 {
         self.msg_duration as f64 / self.num_msg as f64
     }
@@ -93,9 +144,12 @@ impl PerfStats {
     pub fn get_avg_roundtrip_time_hb(&self) -> f64 {
         self.hb_duration as f64 / self.num_hb as f64
     }
+}
+
 
-    pub fn to_string(&self) -> String {
-        format!("{},{},{},{},{},{},{},{},{}",
+impl fmt::Display for PerfStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{},{},{},{},{},{}",
             self.total, self.num_msg, self.num_msg_echo, self.num_hb, self.num_hb_echo,
             self.msg_duration, self.hb_duration,
             self.get_avg_roundtrip_time_msg(), self.get_avg_roundtrip_time_hb())
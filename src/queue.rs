@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use error::TmsnError;
+use Outgoing;
+
+
+/// What `Network::send` does when the outbound queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait until the network sends out a packet (default)
+    #[default]
+    Block,
+    /// Fail with `TmsnError::WouldBlock`
+    WouldBlock,
+    /// Drop the oldest packet in the queue to make room for the new packet
+    DropOldest,
+    /// Drop the new packet
+    DropNewest,
+}
+
+
+struct QueueState<T> {
+    packets: VecDeque<T>,
+    closed: bool,
+    num_dropped: usize,
+}
+
+
+struct QueueInner<T> {
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+}
+
+
+/// What the network does when a subscriber falls too far behind, i.e. its queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowPeerPolicy {
+    /// Close the connection to the subscriber (default), which can subscribe again later
+    #[default]
+    Disconnect,
+    /// Drop the oldest packet in the queue of the subscriber
+    DropOldest,
+    /// Drop the new packet for the subscriber
+    DropNewest,
+}
+
+
+impl SlowPeerPolicy {
+    /// Get the policy of the queue of a subscriber.
+    /// The queue of a subscriber to be disconnected refuses the new packet when it is full.
+    pub fn queue_policy(self) -> QueuePolicy {
+        match self {
+            SlowPeerPolicy::Disconnect => QueuePolicy::WouldBlock,
+            SlowPeerPolicy::DropOldest => QueuePolicy::DropOldest,
+            SlowPeerPolicy::DropNewest => QueuePolicy::DropNewest,
+        }
+    }
+}
+
+
+/// Queue of the packets waiting to be sent out, shared by the application and the network
+///
+/// The queue holds at most `capacity` packets, and applies `policy` when it is full.
+pub struct OutboundQueue<T = Outgoing> {
+    inner: Arc<QueueInner<T>>,
+}
+
+
+impl<T> Clone for OutboundQueue<T> {
+    fn clone(&self) -> OutboundQueue<T> {
+        OutboundQueue { inner: self.inner.clone() }
+    }
+}
+
+
+impl<T> OutboundQueue<T> {
+    /// Create a queue holding at most `capacity` packets, `None` for an unbounded queue
+    pub fn new(capacity: Option<usize>, policy: QueuePolicy) -> OutboundQueue<T> {
+        OutboundQueue {
+            inner: Arc::new(QueueInner {
+                state: Mutex::new(QueueState {
+                    packets: VecDeque::new(),
+                    closed: false,
+                    num_dropped: 0,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+                capacity,
+                policy,
+            }),
+        }
+    }
+
+    /// Add a packet to the queue, waiting for room if the policy is `QueuePolicy::Block`
+    pub fn push(&self, packet: T) -> Result<(), TmsnError> {
+        self.push_until(packet, None)
+    }
+
+    /// Add a packet to the queue without waiting.
+    /// Fails with `TmsnError::WouldBlock` if the queue is full and the policy drops no packet.
+    pub fn try_push(&self, packet: T) -> Result<(), TmsnError> {
+        self.push_until(packet, Some(Instant::now()))
+    }
+
+    /// Add a packet to the queue, waiting for room for at most `timeout`.
+    /// Fails with `TmsnError::Timeout` if the queue is still full by then.
+    pub fn push_timeout(&self, packet: T, timeout: Duration) -> Result<(), TmsnError> {
+        match self.push_until(packet, Some(Instant::now() + timeout)) {
+            Err(TmsnError::WouldBlock) => Err(TmsnError::Timeout),
+            ret => ret,
+        }
+    }
+
+    /// Add a packet to the queue even if it is full, neither waiting nor dropping a packet.
+    /// It is meant for the packets of the network itself, e.g. the heartbeats and the receipts,
+    /// which must not push the packets of the application out of the queue.
+    pub fn force_push(&self, packet: T) -> Result<(), TmsnError> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(TmsnError::Closed);
+        }
+        state.packets.push_back(packet);
+        self.inner.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Add a packet to the queue, waiting for room until `deadline`, or forever if it is `None`
+    fn push_until(&self, packet: T, deadline: Option<Instant>) -> Result<(), TmsnError> {
+        let inner = &self.inner;
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(TmsnError::Closed);
+            }
+            let is_full = inner.capacity
+                .map(|capacity| state.packets.len() >= capacity)
+                .unwrap_or(false);
+            if !is_full {
+                break;
+            }
+            match inner.policy {
+                QueuePolicy::DropOldest => {
+                    state.packets.pop_front();
+                    state.num_dropped += 1;
+                    break;
+                },
+                QueuePolicy::DropNewest => {
+                    state.num_dropped += 1;
+                    return Ok(());
+                },
+                QueuePolicy::WouldBlock => return Err(TmsnError::WouldBlock),
+                QueuePolicy::Block => {
+                    state = match deadline {
+                        None => inner.not_full.wait(state)
+                            .unwrap_or_else(|err| err.into_inner()),
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                return Err(TmsnError::WouldBlock);
+                            }
+                            inner.not_full.wait_timeout(state, deadline - now)
+                                .unwrap_or_else(|err| err.into_inner()).0
+                        },
+                    };
+                },
+            }
+        }
+        state.packets.push_back(packet);
+        inner.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Take the next packet from the queue, waiting for at most `timeout`
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let mut state = self.lock();
+        if state.packets.is_empty() && !state.closed {
+            state = self.inner.not_empty.wait_timeout(state, timeout)
+                .unwrap_or_else(|err| err.into_inner()).0;
+        }
+        let packet = state.packets.pop_front();
+        if packet.is_some() {
+            self.inner.not_full.notify_one();
+        }
+        packet
+    }
+
+    /// Take all packets from the queue
+    pub fn drain(&self) -> Vec<T> {
+        let packets = self.lock().packets.drain(..).collect();
+        self.inner.not_full.notify_all();
+        packets
+    }
+
+    /// Refuse the new packets, and wake up everyone waiting on the queue.
+    /// The packets already in the queue can still be taken.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.inner.not_empty.notify_all();
+        self.inner.not_full.notify_all();
+    }
+
+    /// Check if the queue has been closed
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Get the number of packets in the queue
+    pub fn len(&self) -> usize {
+        self.lock().packets.len()
+    }
+
+    /// Check if the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of packets dropped because the queue was full
+    pub fn num_dropped(&self) -> usize {
+        self.lock().num_dropped
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState<T>> {
+        self.inner.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use packet::Packet;
+    use super::OutboundQueue;
+    use super::QueuePolicy;
+
+    #[test]
+    fn test_outbound_queue() {
+        let packet = |content: &str| (None, Packet::new(content.as_bytes().to_vec()));
+        let content = |packets: Vec<(Option<String>, Packet)>| -> Vec<String> {
+            packets.into_iter().map(|(_, p)| String::from_utf8(p.content).unwrap()).collect()
+        };
+        let oldest = OutboundQueue::new(Some(2), QueuePolicy::DropOldest);
+        let newest = OutboundQueue::new(Some(2), QueuePolicy::DropNewest);
+        for queue in [&oldest, &newest] {
+            for c in ["a", "b", "c"] {
+                queue.push(packet(c)).unwrap();
+            }
+            assert_eq!(queue.num_dropped(), 1);
+        }
+        assert_eq!(content(oldest.drain()), vec!["b", "c"]);
+        assert_eq!(content(newest.drain()), vec!["a", "b"]);
+
+        // the packets of the network itself push out no packet of the application
+        oldest.push(packet("a")).unwrap();
+        oldest.push(packet("b")).unwrap();
+        oldest.force_push(packet("receipt")).unwrap();
+        assert_eq!(oldest.num_dropped(), 1);
+        assert_eq!(content(oldest.drain()), vec!["a", "b", "receipt"]);
+    }
+}
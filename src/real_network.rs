@@ -1,116 +1,472 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
 use std::thread::sleep;
 use std::time::Duration;
+use std::time::Instant;
 
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 
+use builder::NetworkConfig;
+use builder::NetworkMode;
+use codec::Codec;
+use delivery::Deliveries;
+use delivery::DeliveryHandle;
+use error::TmsnError;
+use liveness::Connection;
+use liveness::FailureDetector;
+use liveness::PeerEvent;
+use liveness::PeerEvents;
+use liveness::PeerStatus;
 use network;
+use network::AddressBook;
+use network::NetworkThreads;
+use network::PeerQueueOptions;
+use network::ReorderOptions;
+use network::SocketOptions;
+use network::StopSignal;
+use network::read_lock;
+use network::write_lock;
 use packet::Packet;
+use packet::WireFormat;
 use perfstats::PerfStats;
+use queue::OutboundQueue;
+use retry::RetryPolicy;
+use BytesCallback;
+use EventCallback;
 use HEAD_NODE;
 use LockedStream;
+use PacketCallback;
+use Outgoing;
+use ReadyReport;
+use ShutdownReport;
 
 
-pub struct RealNetwork {
-    outbound_put: Sender<(Option<String>, Packet)>,
+pub struct RealNetwork<C: Codec> {
+    codec: Arc<C>,
+    outbound_put: OutboundQueue,
     perf_stats: Arc<RwLock<PerfStats>>,
     heartbeat_interv_secs: Arc<RwLock<u64>>,
+    wire_format: Arc<RwLock<WireFormat>>,
     send_streams: LockedStream,
+    remote_ips: Vec<String>,
+    port: u16,
+    mode: NetworkMode,
+    head_node: String,
+    address_book: AddressBook,
+    stop_signal: StopSignal,
+    threads: Option<NetworkThreads>,
+    heartbeat_thread: Option<JoinHandle<()>>,
+    deliveries: Deliveries,
+    retransmit_thread: Option<JoinHandle<()>>,
+    detector: FailureDetector,
+    detector_thread: Option<JoinHandle<()>>,
+    events: PeerEvents,
 }
 
 
-impl RealNetwork {
+impl<C: Codec> RealNetwork<C> {
     /// Create a new Network object
     ///
     /// Parameters:
-    ///   * `name` - the local computer name.
-    ///   * `port` - the port number that the machines in the network are listening to.
-    ///   `port` has to be the same value for all machines.
-    ///   * `remote_ips` - a list of IPs to which this computer makes a connection initially.
+    ///   * `port` - the port number that this computer is listening to.
+    ///   * `remote_ips` - a list of addresses to which this computer makes a connection
+    ///     initially, as `host:port`, or as `host` for the computers listening on `port`.
     ///   * `callback` - a callback function to be called when a new packet is received
+    ///   * `codec` - the encoding of the payloads
+    ///   * `retry_policy` - how the connections to the remote machines are retried
     pub fn new<T: 'static + DeserializeOwned>(
         port: u16,
-        remote_ips: &Vec<String>,
+        remote_ips: &[String],
+        callback: Box<dyn FnMut(String, T) + Sync + Send>,
+        codec: C,
+        retry_policy: RetryPolicy,
+    ) -> Result<RealNetwork<C>, TmsnError> {
+        let config = NetworkConfig {
+            remote_ips: remote_ips.to_vec(),
+            retry_policy,
+            ..NetworkConfig::new(port)
+        };
+        RealNetwork::with_config(config, callback, codec)
+    }
+
+    /// Create a new Network object that passes the raw payload bytes to the callback
+    ///
+    /// Parameters are the same as `RealNetwork::new`.
+    pub fn new_bytes(
+        port: u16,
+        remote_ips: &[String],
+        callback: BytesCallback,
+        codec: C,
+        retry_policy: RetryPolicy,
+    ) -> Result<RealNetwork<C>, TmsnError> {
+        let config = NetworkConfig {
+            remote_ips: remote_ips.to_vec(),
+            retry_policy,
+            ..NetworkConfig::new(port)
+        };
+        RealNetwork::with_config_bytes(config, callback, codec)
+    }
+
+    /// Create a new Network object with all options given in `config`
+    /// (see `NetworkBuilder`)
+    pub fn with_config<T: 'static + DeserializeOwned>(
+        config: NetworkConfig,
         mut callback: Box<dyn FnMut(String, T) + Sync + Send>,
-    ) -> RealNetwork {
+        codec: C,
+    ) -> Result<RealNetwork<C>, TmsnError> {
+        let codec = Arc::new(codec);
+        let decoder = codec.clone();
+        RealNetwork::start(config, Box::new(move |sender_name, content: &[u8]| {
+            match decoder.decode::<T>(content) {
+                Ok(content) => callback(sender_name, content),
+                Err(err) => error!("Cannot decode the packet from {}. Error: {}",
+                                   sender_name, err),
+            }
+        }), codec)
+    }
+
+    /// Create a new Network object with all options given in `config`,
+    /// which passes the raw payload bytes to the callback
+    pub fn with_config_bytes(
+        config: NetworkConfig,
+        callback: BytesCallback,
+        codec: C,
+    ) -> Result<RealNetwork<C>, TmsnError> {
+        RealNetwork::start(config, callback, Arc::new(codec))
+    }
+
+    fn start(
+        config: NetworkConfig,
+        mut callback: BytesCallback,
+        codec: Arc<C>,
+    ) -> Result<RealNetwork<C>, TmsnError> {
         // start the network
-        let (outbound_put, outbound_pop):
-            (Sender<(Option<String>, Packet)>, Receiver<(Option<String>, Packet)>)
-            = mpsc::channel();
+        let outbound_put = OutboundQueue::new(config.queue_capacity, config.queue_policy);
         let perf_stats = Arc::new(RwLock::new(PerfStats::new()));
         let ps = perf_stats.clone();
-        let sender_state = network::start_network(
-            remote_ips, port, true, outbound_put.clone(), outbound_pop,
-            Box::new(move |sender_name, packet| {
-                let mut ps = ps.write().unwrap();
-                ps.update(sender_name.clone(), &packet);
-                drop(ps);
-                if packet.is_workload() {
-                    let content: T = serde_json::from_str(&packet.content.unwrap()).unwrap();
-                    callback(sender_name, content);
+        let wire_format = Arc::new(RwLock::new(config.wire_format));
+        let disconnect_ps = perf_stats.clone();
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        let deliveries = Deliveries::new(config.ack_timeout, config.max_retransmits);
+        let acks = deliveries.clone();
+        let events = PeerEvents::new();
+        let detector = FailureDetector::new(
+            config.suspect_timeout, config.dead_timeout, events.clone());
+        let heard_from = detector.clone();
+        let packet_callback: PacketCallback = Box::new(move |sender_name, packet| {
+            // any packet, heartbeats included, shows that the remote machine is alive
+            heard_from.heard_from(sender_name.clone());
+            write_lock(&ps).update(sender_name.clone(), &packet);
+            if let Some(id) = packet.get_acked_id() {
+                acks.ack(&sender_name, id);
+            }
+            if packet.is_workload() {
+                callback(sender_name, &packet.content);
+            }
+        });
+        let membership = events.clone();
+        let on_event: EventCallback = Arc::new(move |event| {
+            if let PeerEvent::PeerDisconnected(ref subscriber, Connection::Subscriber) = event {
+                write_lock(&disconnect_ps).record_disconnect(subscriber.clone());
+            }
+            membership.emit(event);
+        });
+        let peer_queue = PeerQueueOptions {
+            capacity: config.peer_queue_capacity,
+            slow_peer_policy: config.slow_peer_policy,
+            replay_capacity: config.replay_capacity,
+            replay_max_bytes: config.replay_max_bytes,
+            replay_retention: config.replay_retention,
+        };
+        let socket_options = SocketOptions {
+            read_timeout: config.read_timeout,
+            write_timeout: config.write_timeout,
+            keepalive: config.keepalive,
+            nodelay: config.nodelay,
+        };
+        let reorder = config.reorder_window.map(|window| {
+            ReorderOptions { window, timeout: config.reorder_timeout }
+        });
+        let (send_streams, threads) = match config.mode {
+            NetworkMode::TwoWay | NetworkMode::OneWay => network::start_network(
+                &config.remote_ips, config.bind_addr, config.port, config.node_id,
+                config.mode == NetworkMode::TwoWay,
+                outbound_put.clone(), packet_callback, wire_format.clone(), peer_queue,
+                socket_options, reorder, on_event, config.retry_policy,
+                stop_signal.clone(),
+            )?,
+            NetworkMode::SendOnly => network::start_network_only_send(
+                config.bind_addr, config.port, config.node_id, outbound_put.clone(),
+                wire_format.clone(), peer_queue, socket_options,
+                on_event, stop_signal.clone(),
+            )?,
+            NetworkMode::ReceiveOnly => {
+                let threads = network::start_network_only_recv(
+                    &config.remote_ips, config.port, config.node_id, packet_callback,
+                    config.retry_policy, socket_options, reorder, on_event, stop_signal.clone(),
+                )?;
+                (Arc::new(RwLock::new(vec![])), threads)
+            },
+        };
+
+        // the remote machines without a port are assumed to listen on the same port
+        let port = threads.get_local_addr().map(|addr| addr.port()).unwrap_or(config.port);
+
+        // send heart beat signals to all subscribers, so that they can tell this machine is alive
+        let heartbeat_interv_secs = Arc::new(RwLock::new(config.heartbeat_interval_secs));
+        let head_node = config.head_node.unwrap_or_else(|| HEAD_NODE.to_string());
+        let address_book = threads.get_address_book();
+        let heartbeat_thread = if config.mode != NetworkMode::ReceiveOnly {
+            let outbound = outbound_put.clone();
+            let interval = heartbeat_interv_secs.clone();
+            let ps = perf_stats.clone();
+            let heartbeat_stop = stop_signal.clone();
+            let streams = send_streams.clone();
+            Some(std::thread::spawn(move|| {
+                while !network::is_stopped(&heartbeat_stop) {
+                    // the heartbeats are not queued up before anyone can receive them
+                    if read_lock(&streams).is_empty() {
+                        network::sleep_or_stop(network::POLL_INTERVAL, &heartbeat_stop);
+                        continue;
+                    }
+                    let heartbeat = Packet::get_hb(&read_lock(&ps));
+                    // the heartbeats neither wait for room in the queue nor take it
+                    match outbound.force_push((None, heartbeat)) {
+                        Err(TmsnError::Closed) => break,
+                        Err(err) => trace!("Skipped a heartbeat. Error: {}", err),
+                        Ok(()) => {},
+                    }
+
+                    let secs = *read_lock(&interval);
+                    network::sleep_or_stop(Duration::from_secs(secs), &heartbeat_stop);
+                }
+            }))
+        } else {
+            None
+        };
+
+        // retransmit the packets sent with `send_reliable` that were not acknowledged in time
+        let retransmit_thread = if config.mode != NetworkMode::ReceiveOnly {
+            let deliveries = deliveries.clone();
+            let outbound = outbound_put.clone();
+            let retransmit_stop = stop_signal.clone();
+            Some(std::thread::spawn(move|| {
+                while !network::is_stopped(&retransmit_stop) {
+                    for packet in deliveries.get_expired() {
+                        // a retransmission neither waits for room in the queue nor takes it
+                        match outbound.force_push(packet) {
+                            Err(TmsnError::Closed) => return,
+                            Err(err) => trace!("Skipped a retransmission. Error: {}", err),
+                            Ok(()) => {},
+                        }
+                    }
+                    network::sleep_or_stop(network::POLL_INTERVAL, &retransmit_stop);
+                }
+            }))
+        } else {
+            None
+        };
+
+        // suspect the remote machines that have gone silent
+        let detector_thread = {
+            let detector = detector.clone();
+            let detector_stop = stop_signal.clone();
+            Some(std::thread::spawn(move|| {
+                while !network::is_stopped(&detector_stop) {
+                    detector.check();
+                    network::sleep_or_stop(network::POLL_INTERVAL, &detector_stop);
                 }
-            }));
+            }))
+        };
+
+        Ok(RealNetwork {
+            codec,
+            outbound_put,
+            perf_stats,
+            heartbeat_interv_secs,
+            wire_format,
+            send_streams,
+            remote_ips: config.remote_ips,
+            port,
+            mode: config.mode,
+            head_node,
+            address_book,
+            stop_signal,
+            threads: Some(threads),
+            heartbeat_thread,
+            deliveries,
+            retransmit_thread,
+            detector,
+            detector_thread,
+            events,
+        })
+    }
+
+    /// Get the address this machine is listening on, which has the port assigned by the OS
+    /// if the network was created with port 0
+    ///
+    /// Returns `None` in the receive-only mode, or after the network is shut down.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.threads.as_ref().and_then(|threads| threads.get_local_addr())
+    }
+
+    /// Get the list of the node IDs of the subscribed machines
+    pub fn get_subscribers(&self) -> Vec<String> {
+        read_lock(&self.send_streams).iter().map(|s| s.name.clone()).collect()
+    }
 
-        // check if network is ready
-        let send_streams = sender_state.unwrap();
+    /// Get the list of the node IDs of the machines this machine is subscribed to
+    pub fn get_subscriptions(&self) -> Vec<String> {
+        self.threads.as_ref().map(|threads| threads.get_subscriptions()).unwrap_or_default()
+    }
+
+    /// Get the liveness of the remote machines this machine has received packets from,
+    /// and the last time a packet arrived from each of them, by their node IDs
+    ///
+    /// A remote machine is suspected after sending nothing, not even a heartbeat, for
+    /// `suspect_timeout`, and considered dead after `dead_timeout`. Nothing is received in
+    /// the send-only mode, so no remote machine is reported.
+    pub fn peer_status(&self) -> HashMap<String, PeerStatus> {
+        self.detector.get_status()
+    }
+
+    /// Get a channel that receives the membership events from now on
+    ///
+    /// The connections are reported on both sides, i.e. as `Connection::Subscriber` when
+    /// a remote machine subscribes to this machine, and as `Connection::Subscription` when
+    /// this machine subscribes to a remote machine. The remote machines are suspected and
+    /// recover according to `peer_status`. No events are sent once the network is shut down.
+    pub fn peer_events(&self) -> Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Wait until at least `min_peers` of the initial remote machines have subscribed to
+    /// this machine, or `timeout` has passed
+    ///
+    /// In the receive-only mode, it waits for this machine to subscribe to the initial
+    /// remote machines instead.
+    ///
+    /// The network starts without waiting for the remote machines, so that it can run
+    /// with a partial cluster. Returns the machines that are connected and the initial
+    /// remote machines that are still missing.
+    pub fn wait_ready(&self, timeout: Duration, min_peers: usize) -> ReadyReport {
+        let deadline = Instant::now() + timeout;
         loop {
-            let s = send_streams.read().unwrap();
-            if s.len() == remote_ips.len() {
-                break;
+            let report = self.get_ready_report(min_peers);
+            let now = Instant::now();
+            if report.is_ready() || now >= deadline || network::is_stopped(&self.stop_signal) {
+                return report;
             }
-            drop(s);
-            sleep(Duration::from_millis(500));
+            sleep(network::POLL_INTERVAL.min(deadline - now));
         }
+    }
 
-        // send heart beat signals
-        let heartbeat_interv_secs = Arc::new(RwLock::new(30));
-        let head_ip = HEAD_NODE.to_string();
-        let outbound = outbound_put.clone();
-        let interval = heartbeat_interv_secs.clone();
-        let ps = perf_stats.clone();
-        std::thread::spawn(move|| {
-            loop {
-                let ps = ps.read().unwrap();
-                outbound.send((Some(head_ip.clone()), Packet::get_hb(&ps))).unwrap();
-                drop(ps);
-
-                let interval = interval.read().unwrap();
-                let secs = *interval;
-                drop(interval);
-                sleep(Duration::from_secs(secs));
-            }
-        });
+    fn get_ready_report(&self, min_peers: usize) -> ReadyReport {
+        let connected = if self.mode == NetworkMode::ReceiveOnly {
+            self.get_subscriptions()
+        } else {
+            self.get_subscribers()
+        };
+        let missing = self.remote_ips.iter()
+            .filter(|ip| {
+                !connected.contains(&network::resolve_node_id(&self.address_book, ip, self.port))
+            })
+            .cloned()
+            .collect();
+        ReadyReport { connected, missing, min_peers }
+    }
+
+    /// Send out a packet
+    ///
+    /// `dest` is either the node ID or the address of a subscriber.
+    /// Fails if the payload cannot be encoded, `dest` is not a subscriber,
+    /// or the network has been shut down.
+    /// If the outbound queue is full, the queue policy applies.
+    pub fn send<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T,
+    ) -> Result<(), TmsnError> {
+        let payload = self.codec.encode(&packet_load)?;
+        self.send_bytes(dest, payload)
+    }
+
+    /// Send out a packet without waiting for room in the outbound queue
+    ///
+    /// Fails with `TmsnError::WouldBlock` if the queue is full, unless the queue policy
+    /// drops a packet instead.
+    pub fn try_send<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T,
+    ) -> Result<(), TmsnError> {
+        let payload = self.codec.encode(&packet_load)?;
+        self.outbound_put.try_push(self.get_outgoing(dest, payload)?)
+    }
+
+    /// Send out a packet, waiting for room in the outbound queue for at most `timeout`
+    ///
+    /// Fails with `TmsnError::Timeout` if the queue is still full by then.
+    pub fn send_timeout<T: Serialize>(
+        &self, dest: Option<String>, packet_load: T, timeout: Duration,
+    ) -> Result<(), TmsnError> {
+        let payload = self.codec.encode(&packet_load)?;
+        self.outbound_put.push_timeout(self.get_outgoing(dest, payload)?, timeout)
+    }
 
-        RealNetwork {
-            outbound_put: outbound_put.clone(),
-            perf_stats: perf_stats,
-            heartbeat_interv_secs: heartbeat_interv_secs,
-            send_streams: send_streams,
+    /// Send out a packet to `dest`, and retransmit it until `dest` acknowledges it
+    ///
+    /// Returns a handle that tells whether the packet was delivered.
+    pub fn send_reliable<T: Serialize>(
+        &self, dest: String, packet_load: T,
+    ) -> Result<DeliveryHandle, TmsnError> {
+        let payload = self.codec.encode(&packet_load)?;
+        let (dest, packet) = self.get_outgoing(Some(dest), payload)?;
+        let id = packet.id;
+        // tracked before it is sent out, in case the acknowledgement arrives first
+        let handle = self.deliveries.track(dest.clone(), packet.clone());
+        if let Err(err) = self.outbound_put.push((dest, packet)) {
+            self.deliveries.forget(id);
+            return Err(err);
         }
+        Ok(handle)
     }
 
-    /// Get the list of the address of the subscribed machines
-    pub fn get_subscribers(&mut self) -> Vec<String> {
-        let streams = self.send_streams.read().unwrap();
-        let subscribers: Vec<String> = streams.iter().map(|(s, _)| s.clone()).collect();
-        drop(streams);
-        subscribers
+    /// Send out a packet whose payload is already encoded, bypassing the codec
+    pub fn send_bytes(&self, dest: Option<String>, payload: Vec<u8>) -> Result<(), TmsnError> {
+        self.outbound_put.push(self.get_outgoing(dest, payload)?)
     }
 
-    /// Send out a packet
-    pub fn send<T: Serialize>(&self, dest: Option<String>, packet_load: T) -> Result<(), ()> {
-        let safe_json = serde_json::to_string(&packet_load).unwrap();
-        let ret = self.outbound_put.send((dest, Packet::new(safe_json)));
-        if ret.is_ok() {
-            Ok(())
-        } else {
-            Err(())
+    // Check the destination, and create the packet to be sent out
+    fn get_outgoing(
+        &self, dest: Option<String>, payload: Vec<u8>,
+    ) -> Result<Outgoing, TmsnError> {
+        if network::is_stopped(&self.stop_signal) {
+            return Err(TmsnError::Closed);
+        }
+        if self.mode == NetworkMode::ReceiveOnly {
+            return Err(TmsnError::Unsupported("Sending".to_string()));
         }
+        // the packets to the head node are sent to the designated head node, if any
+        let is_subscriber = |dest: &str| {
+            dest == HEAD_NODE || read_lock(&self.send_streams).iter().any(|s| s.name == dest)
+        };
+        let dest = dest
+            .map(|dest| if dest == HEAD_NODE { self.head_node.clone() } else { dest })
+            .map(|dest| if is_subscriber(&dest) {
+                dest
+            } else {
+                network::resolve_node_id(&self.address_book, &dest, self.port)
+            });
+        if let Some(ref dest) = dest {
+            if !is_subscriber(dest) {
+                return Err(TmsnError::UnknownPeer(dest.clone()));
+            }
+        }
+        Ok((dest, Packet::new(payload)))
     }
 
     /// Set heartbeat interval
@@ -119,13 +475,82 @@ impl RealNetwork {
     ///   * hb_interval_secs: the time interval between sending out the heartbeat signals
     ///     (unit: seconds)
     pub fn set_health_parameter(&mut self, hb_interval_secs: u64) {
-        let mut val = self.heartbeat_interv_secs.write().unwrap();
-        *val = hb_interval_secs;
+        *write_lock(&self.heartbeat_interv_secs) = hb_interval_secs;
+    }
+
+    /// Set the encoding of the outgoing packets
+    ///
+    /// Parameter:
+    ///   * wire_format: `WireFormat::Binary` (default) or `WireFormat::Json` for the
+    ///     newline-delimited JSON frames understood by the older versions of tmsn
+    pub fn set_wire_format(&mut self, wire_format: WireFormat) {
+        *write_lock(&self.wire_format) = wire_format;
     }
 
     /// Return a summary of the network communication
     pub fn get_health(&self) -> PerfStats {
-        let ps = self.perf_stats.read().unwrap();
-        (*ps).clone()
+        let mut perf_stats = read_lock(&self.perf_stats).clone();
+        perf_stats.outbound_depth = self.outbound_put.len();
+        read_lock(&self.send_streams).iter().for_each(|subscriber| {
+            perf_stats.queue_depths.insert(subscriber.name.clone(), subscriber.queue_depth());
+            perf_stats.queue_dropped.insert(subscriber.name.clone(), subscriber.num_dropped());
+        });
+        let sequences = self.threads.as_ref()
+            .map(|threads| threads.get_sequence_stats())
+            .unwrap_or_default();
+        for (name, stats) in sequences {
+            perf_stats.num_lost.insert(name.clone(), stats.num_lost);
+            perf_stats.num_duplicates.insert(name.clone(), stats.num_duplicates);
+            perf_stats.num_reordered.insert(name, stats.num_reordered);
+        }
+        perf_stats
     }
-}
\ No newline at end of file
+
+    /// Shut down the network
+    ///
+    /// The packets still in the outbound queue are sent out, then all streams are closed,
+    /// the listening port is released, and all network threads are stopped.
+    /// Returns a report of the packets that could not be delivered.
+    /// The packets sent with `send_reliable` that have not been acknowledged are marked
+    /// as failed.
+    /// Calling it more than once returns an empty report.
+    pub fn shutdown(&mut self) -> ShutdownReport {
+        self.stop_signal.store(true, Ordering::SeqCst);
+        // wake up the application waiting for room in the queue
+        self.outbound_put.close();
+        let mut report = ShutdownReport::default();
+        if let Some(heartbeat_thread) = self.heartbeat_thread.take() {
+            if heartbeat_thread.join().is_err() {
+                report.errors.push("The heartbeat thread panicked.".to_string());
+            }
+        }
+        if let Some(retransmit_thread) = self.retransmit_thread.take() {
+            if retransmit_thread.join().is_err() {
+                report.errors.push("The retransmission thread panicked.".to_string());
+            }
+        }
+        if let Some(detector_thread) = self.detector_thread.take() {
+            if detector_thread.join().is_err() {
+                report.errors.push("The failure detector thread panicked.".to_string());
+            }
+        }
+        if let Some(threads) = self.threads.take() {
+            let threads_report = threads.join();
+            report.undelivered = threads_report.undelivered;
+            report.errors.extend(threads_report.errors);
+        }
+        self.deliveries.fail_all();
+        report
+    }
+}
+
+
+impl<C: Codec> Drop for RealNetwork<C> {
+    fn drop(&mut self) {
+        let report = self.shutdown();
+        if !report.is_clean() {
+            error!("Network shut down with {} undelivered packets. Errors: {:?}",
+                   report.undelivered.len(), report.errors);
+        }
+    }
+}
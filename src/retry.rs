@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use rand::Rng;
+use rand::thread_rng;
+
+
+/// Policy for retrying a failed connection to a remote machine
+///
+/// The policy applies both to the initial connection and to the reconnection after
+/// a connection is lost. A failed hello exchange counts as a failed attempt too, while
+/// an incompatible remote machine is not retried. The delay before the `n`-th retry is
+/// `initial_delay * backoff_factor^(n - 1)`, capped at `max_delay`,
+/// and then randomly shifted by up to `jitter` of its length.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// maximum number of connection attempts, `None` for retrying forever
+    pub max_attempts: Option<u32>,
+    /// delay before the first retry
+    pub initial_delay: Duration,
+    /// factor by which the delay grows after every failed retry
+    pub backoff_factor: f64,
+    /// upper bound of the delay between two attempts
+    pub max_delay: Duration,
+    /// fraction (between 0 and 1) of the delay to be randomly added or subtracted,
+    /// so that the machines do not retry all at the same time
+    pub jitter: f64,
+}
+
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_secs(1),
+            backoff_factor: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.1,
+        }
+    }
+}
+
+
+impl RetryPolicy {
+    /// Create a policy that keeps retrying forever, with the default delays
+    pub fn unlimited() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: None,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Check if another attempt should be made after `attempts` failed attempts
+    pub fn should_retry(&self, attempts: u32) -> bool {
+        self.max_attempts.map(|max_attempts| attempts < max_attempts).unwrap_or(true)
+    }
+
+    /// Get the delay before the next attempt after `attempts` failed attempts
+    pub fn get_delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let max_delay = self.max_delay.as_secs_f64();
+        let delay = (self.initial_delay.as_secs_f64() * self.backoff_factor.powi(exponent))
+            .min(max_delay);
+        let delay = if self.jitter > 0.0 {
+            delay * (1.0 + thread_rng().gen_range(-self.jitter, self.jitter))
+        } else {
+            delay
+        };
+        if delay.is_finite() && delay > 0.0 {
+            Duration::from_secs_f64(delay)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            initial_delay: Duration::from_millis(100),
+            backoff_factor: 2.0,
+            max_delay: Duration::from_millis(300),
+            jitter: 0.0,
+        };
+        assert!(policy.should_retry(2));
+        assert!(!policy.should_retry(3));
+        assert_eq!(policy.get_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.get_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.get_delay(3), Duration::from_millis(300));
+        assert!(RetryPolicy::unlimited().should_retry(u32::MAX));
+
+        let jittered = RetryPolicy { jitter: 0.5, ..policy };
+        let delay = jittered.get_delay(1);
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+    }
+}